@@ -1,8 +1,18 @@
+use std::path::PathBuf;
+
 use structopt::StructOpt;
 
+use crate::{
+    client_auth::ClientAuthPublicKey, endpoint::LocalEndpoint, keystore::PassphraseSource,
+    onion::OnionV2Policy, security::Security, socks::IsolationPolicy,
+};
+
 #[derive(Debug, StructOpt)]
 #[structopt(name = "ping-pong", about = "libp2p ping-pong application over Tor.")]
 pub struct Opt {
+    #[structopt(subcommand)]
+    pub cmd: Option<Command>,
+
     /// Run as the dialer i.e., do the ping
     #[structopt(short, long)]
     pub dialer: bool,
@@ -14,4 +24,195 @@ pub struct Opt {
     /// Onion mulitaddr to use (only required for dialer)
     #[structopt(long)]
     pub onion: Option<String>,
+
+    /// File holding our libp2p identity, generated if missing [default: new one per run]
+    #[structopt(long, parse(from_os_str))]
+    pub identity: Option<PathBuf>,
+
+    /// Encrypted keystore holding our identity and onion service key, created if missing
+    #[structopt(long, conflicts_with = "identity", parse(from_os_str))]
+    pub keystore: Option<PathBuf>,
+
+    /// Environment variable holding the keystore passphrase [default: prompt]
+    #[structopt(long)]
+    pub passphrase_env: Option<String>,
+
+    /// File descriptor to read the keystore passphrase from [default: prompt]
+    #[structopt(long, conflicts_with = "passphrase-env")]
+    pub passphrase_fd: Option<u32>,
+
+    /// torrc configuring the listener's onion services
+    #[structopt(long, default_value = "torrc", parse(from_os_str))]
+    pub torrc: PathBuf,
+
+    /// Start a private Tor instead of using the system Tor
+    #[structopt(long)]
+    pub launch_tor: bool,
+
+    /// Tor binary to run with --launch-tor
+    #[structopt(long, default_value = "tor", parse(from_os_str))]
+    pub tor_binary: PathBuf,
+
+    /// Seconds to wait for Tor to bootstrap, the dialer waits if it has a control port
+    #[structopt(long, default_value = "120")]
+    pub bootstrap_timeout: u64,
+
+    /// Tor control port, used to create an ephemeral onion service and to wait
+    /// for bootstrap. Either host:port or unix:/path/to/socket
+    #[structopt(long)]
+    pub control_port: Option<LocalEndpoint>,
+
+    /// Password for HASHEDPASSWORD control port authentication
+    #[structopt(long)]
+    pub control_password: Option<String>,
+
+    /// Control port auth cookie, if not the file advertised by Tor
+    #[structopt(long, parse(from_os_str))]
+    pub control_cookie: Option<PathBuf>,
+
+    /// Virtual port of the ephemeral onion service
+    #[structopt(long, default_value = "7")]
+    pub onion_port: u16,
+
+    /// Seconds to wait for the ephemeral onion service to be published
+    #[structopt(long, default_value = "180")]
+    pub publish_timeout: u64,
+
+    /// Client authorization public key allowed to reach the ephemeral onion
+    /// service, may be given several times
+    #[structopt(long = "authorized-client", number_of_values = 1)]
+    pub authorized_clients: Vec<ClientAuthPublicKey>,
+
+    /// File with our client authorization private key for the dialed onion
+    /// service, requires a control port
+    #[structopt(long, parse(from_os_str))]
+    pub client_auth_key: Option<PathBuf>,
+
+    /// Use our libp2p identity as the ephemeral onion service key
+    #[structopt(long)]
+    pub onion_from_identity: bool,
+
+    /// Authentication protocols to offer: secio, noise or both
+    #[structopt(long, default_value = "secio")]
+    pub security: Security,
+
+    /// Swarm key file (/key/swarm/psk/1.0.0/), only peers holding it can connect
+    #[structopt(long, parse(from_os_str))]
+    pub swarm_key: Option<PathBuf>,
+
+    /// File listing the only PeerIds allowed to connect, one per line
+    #[structopt(long, parse(from_os_str))]
+    pub allow_peers: Option<PathBuf>,
+
+    /// File listing PeerIds not allowed to connect, one per line
+    #[structopt(long, conflicts_with = "allow-peers", parse(from_os_str))]
+    pub deny_peers: Option<PathBuf>,
+
+    /// Require the dialed onion service's key to be the listener's identity
+    #[structopt(long)]
+    pub verify_onion_identity: bool,
+
+    /// File to load the ephemeral onion service key from, or save a new one to
+    #[structopt(long, parse(from_os_str), conflicts_with = "onion-from-identity")]
+    pub onion_key: Option<PathBuf>,
+
+    /// Tor SOCKS5 proxy, either host:port or unix:/path/to/socket
+    #[structopt(long, default_value = "127.0.0.1:9050")]
+    pub socks: LocalEndpoint,
+
+    /// Tor circuit isolation for dials: shared, per-peer or per-dial
+    #[structopt(long, default_value = "per-peer")]
+    pub isolation: IsolationPolicy,
+
+    /// Resolve host names with Tor's RESOLVE extension before connecting
+    #[structopt(long)]
+    pub tor_resolve: bool,
+
+    /// Allow dialing /ip4, /ip6 and /dns addresses via Tor exit relays
+    #[structopt(long)]
+    pub allow_clearnet: bool,
+
+    /// Policy for deprecated v2 onion addresses: reject, warn or allow
+    #[structopt(long, default_value = "warn")]
+    pub onion_v2: OnionV2Policy,
+
+    /// Expect HAProxy PROXY headers from Tor (HiddenServiceExportCircuitID haproxy)
+    #[structopt(long)]
+    pub proxy_protocol: bool,
+
+    /// Maximum number of concurrent inbound connections
+    #[structopt(long)]
+    pub max_inbound: Option<usize>,
+
+    /// Maximum inbound connections accepted per second
+    #[structopt(long)]
+    pub accept_rate: Option<f64>,
+
+    /// Number of inbound connections that may be accepted in a burst
+    #[structopt(long, default_value = "10")]
+    pub accept_burst: u32,
+
+    /// Backlog of the listening socket
+    #[structopt(long, default_value = "1024")]
+    pub backlog: i32,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Generate an onion client authorization key pair
+    ClientAuthKeygen {
+        /// File to write the private key to
+        #[structopt(parse(from_os_str))]
+        key_file: PathBuf,
+    },
+    /// Print the PeerId of an identity file
+    PeerId {
+        /// Identity file, as written by --identity
+        #[structopt(parse(from_os_str))]
+        key_file: PathBuf,
+    },
+    /// Change the passphrase of a keystore
+    KeystorePassphrase {
+        /// Keystore file, as written by --keystore
+        #[structopt(parse(from_os_str))]
+        keystore: PathBuf,
+
+        /// Environment variable holding the new passphrase [default: prompt]
+        #[structopt(long)]
+        new_passphrase_env: Option<String>,
+
+        /// File descriptor to read the new passphrase from [default: prompt]
+        #[structopt(long, conflicts_with = "new-passphrase-env")]
+        new_passphrase_fd: Option<u32>,
+    },
+    /// Print the PeerId and onion addresses of the keys in a keystore
+    KeystoreExport {
+        /// Keystore file, as written by --keystore
+        #[structopt(parse(from_os_str))]
+        keystore: PathBuf,
+
+        /// Virtual port of the printed onion multiaddrs
+        #[structopt(long, default_value = "7")]
+        port: u16,
+    },
+    /// Generate a Tor HiddenServiceDir with a new onion service key
+    Keygen {
+        /// HiddenServiceDir to write the keys and hostname to
+        #[structopt(parse(from_os_str))]
+        dir: PathBuf,
+
+        /// Virtual port of the printed multiaddr
+        #[structopt(long, default_value = "7")]
+        port: u16,
+    },
+}
+
+/// Where to read a passphrase from, given the `--*passphrase-env` and
+/// `--*passphrase-fd` options.
+pub fn passphrase_source(env: Option<String>, fd: Option<u32>) -> PassphraseSource {
+    match (env, fd) {
+        (Some(var), _) => PassphraseSource::Env(var),
+        (None, Some(fd)) => PassphraseSource::Fd(fd),
+        (None, None) => PassphraseSource::Prompt,
+    }
 }
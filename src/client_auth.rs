@@ -0,0 +1,177 @@
+//! Onion service client authorization (v3 authorized clients).
+//!
+//! A service created with `ClientAuthV3` keys only publishes descriptors
+//! that the holders of the matching x25519 private keys can decrypt. The
+//! dialer hands its private key to Tor with `ONION_CLIENT_AUTH_ADD` before
+//! dialing, see rend-spec-v3.txt and control-spec.txt.
+
+use std::{fmt, fs, io, path::Path, str::FromStr};
+
+use curve25519_dalek::{constants::X25519_BASEPOINT, scalar::Scalar};
+use data_encoding::{BASE32_NOPAD, BASE64};
+use rand::RngCore;
+
+use crate::keys;
+
+/// Length of x25519 keys.
+const KEY_LEN: usize = 32;
+
+/// Prefix of public keys in Tor's `authorized_clients/*.auth` files.
+const AUTH_FILE_PREFIX: &str = "descriptor:x25519:";
+
+/// A client's x25519 private key.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ClientAuthKey([u8; KEY_LEN]);
+
+impl ClientAuthKey {
+    /// Generates a new random key.
+    pub fn generate() -> Self {
+        let mut secret = [0u8; KEY_LEN];
+        rand::thread_rng().fill_bytes(&mut secret);
+        ClientAuthKey(clamp(secret))
+    }
+
+    /// Creates a key from its raw bytes.
+    pub fn from_bytes(secret: [u8; KEY_LEN]) -> Self {
+        ClientAuthKey(secret)
+    }
+
+    /// The matching public key, given to the onion service.
+    pub fn public_key(&self) -> ClientAuthPublicKey {
+        let point = X25519_BASEPOINT * Scalar::from_bits(clamp(self.0));
+        ClientAuthPublicKey(point.to_bytes())
+    }
+
+    /// The key in the base64 form `ONION_CLIENT_AUTH_ADD` expects.
+    pub fn to_base64(&self) -> String {
+        BASE64.encode(&self.0)
+    }
+
+    /// Reads a base64 encoded key from `path`.
+    pub fn read(path: &Path) -> io::Result<Self> {
+        fs::read_to_string(path)?.trim().parse()
+    }
+
+    /// Writes the key base64 encoded to the new file `path`, only readable
+    /// by the current user.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        keys::write_secret(path, format!("{}\n", self.to_base64()).as_bytes())
+    }
+}
+
+impl fmt::Debug for ClientAuthKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ClientAuthKey({})", self.public_key())
+    }
+}
+
+/// Parses a base64 encoded key.
+impl FromStr for ClientAuthKey {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = BASE64.decode(s.as_bytes()).ok();
+        decode_key(bytes, "client auth private key").map(ClientAuthKey)
+    }
+}
+
+/// A client's x25519 public key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientAuthPublicKey([u8; KEY_LEN]);
+
+impl ClientAuthPublicKey {
+    /// The key in Tor's `authorized_clients/*.auth` file format.
+    pub fn auth_file_line(&self) -> String {
+        format!("{}{}", AUTH_FILE_PREFIX, self)
+    }
+}
+
+/// Formats the key in the unpadded base32 form `ClientAuthV3` expects.
+impl fmt::Display for ClientAuthPublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", BASE32_NOPAD.encode(&self.0))
+    }
+}
+
+/// Parses a base32 encoded key, optionally in `*.auth` file format.
+impl FromStr for ClientAuthPublicKey {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.strip_prefix(AUTH_FILE_PREFIX).unwrap_or(s).to_uppercase();
+        let bytes = BASE32_NOPAD.decode(s.as_bytes()).ok();
+        decode_key(bytes, "client auth public key").map(ClientAuthPublicKey)
+    }
+}
+
+fn decode_key(bytes: Option<Vec<u8>>, what: &str) -> io::Result<[u8; KEY_LEN]> {
+    match bytes {
+        Some(bytes) if bytes.len() == KEY_LEN => {
+            let mut key = [0u8; KEY_LEN];
+            key.copy_from_slice(&bytes);
+            Ok(key)
+        }
+        _ => {
+            let msg = format!("invalid {}", what);
+            Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+        }
+    }
+}
+
+// x25519 scalar clamping, see RFC 7748.
+fn clamp(mut secret: [u8; KEY_LEN]) -> [u8; KEY_LEN] {
+    secret[0] &= 248;
+    secret[31] &= 127;
+    secret[31] |= 64;
+    secret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data_encoding::HEXLOWER;
+
+    fn hex(s: &str) -> [u8; KEY_LEN] {
+        let mut key = [0u8; KEY_LEN];
+        key.copy_from_slice(&HEXLOWER.decode(s.as_bytes()).unwrap());
+        key
+    }
+
+    #[test]
+    fn public_key_matches_rfc7748() {
+        // Alice's keys from RFC 7748 section 6.1.
+        let secret = hex("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+        let public = hex("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a");
+
+        assert_eq!(
+            ClientAuthKey::from_bytes(secret).public_key(),
+            ClientAuthPublicKey(public)
+        );
+    }
+
+    #[test]
+    fn keys_round_trip_through_tor_formats() {
+        let key = ClientAuthKey::generate();
+        let public = key.public_key();
+
+        assert_eq!(key.to_base64().parse::<ClientAuthKey>().unwrap(), key);
+        assert_eq!(public.to_string().len(), 52);
+        assert_eq!(
+            public.to_string().parse::<ClientAuthPublicKey>().unwrap(),
+            public
+        );
+        assert_eq!(
+            public
+                .auth_file_line()
+                .parse::<ClientAuthPublicKey>()
+                .unwrap(),
+            public
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_key() {
+        assert!("AAAA".parse::<ClientAuthPublicKey>().is_err());
+        assert!("AAAA".parse::<ClientAuthKey>().is_err());
+    }
+}
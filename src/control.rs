@@ -0,0 +1,1201 @@
+//! Client for the Tor control protocol, see Tor's control-spec.txt.
+//!
+//! We use it to create ephemeral onion services with `ADD_ONION` so that
+//! the listener does not depend on a hand-edited `torrc`.
+//!
+//! `ControlConfig::connect` authenticates with any of the methods Tor
+//! advertises in its `PROTOCOLINFO` reply: NULL, HASHEDPASSWORD, COOKIE or
+//! SAFECOOKIE.
+
+use std::{
+    collections::VecDeque,
+    fmt, fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use data_encoding::{BASE64, HEXLOWER_PERMISSIVE, HEXUPPER};
+use futures_timer::Delay;
+use hmac::{Hmac, Mac};
+use libp2p::{identity, Multiaddr};
+use log::{debug, info, warn};
+use rand::RngCore;
+use sha2::Sha256;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+
+use crate::{
+    client_auth::{ClientAuthKey, ClientAuthPublicKey},
+    endpoint::{LocalEndpoint, LocalStream},
+    onion::{expand_secret_key, OnionAddress},
+};
+
+/// Default control port of a system Tor.
+pub const DEFAULT_CONTROL_PORT: u16 = 9051;
+
+const STATUS_OK: u16 = 250;
+const STATUS_EVENT: u16 = 650;
+
+/// How often we ask Tor for its bootstrap progress.
+const BOOTSTRAP_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Length of the authentication cookie and of SAFECOOKIE nonces.
+const COOKIE_LEN: usize = 32;
+
+const SAFECOOKIE_SERVER_KEY: &[u8] = b"Tor safe cookie authentication server-to-controller hash";
+const SAFECOOKIE_CLIENT_KEY: &[u8] = b"Tor safe cookie authentication controller-to-server hash";
+
+/// How to connect and authenticate to the control port.
+#[derive(Debug, Clone)]
+pub struct ControlConfig {
+    endpoint: LocalEndpoint,
+    password: Option<String>,
+    cookie_file: Option<PathBuf>,
+}
+
+impl ControlConfig {
+    /// Connects to `endpoint`, by default using whichever of the NULL,
+    /// SAFECOOKIE or COOKIE methods Tor offers.
+    pub fn new(endpoint: LocalEndpoint) -> Self {
+        ControlConfig {
+            endpoint,
+            password: None,
+            cookie_file: None,
+        }
+    }
+
+    /// Authenticates with `password` if Tor offers HASHEDPASSWORD.
+    pub fn password(mut self, password: impl Into<String>) -> Self {
+        self.password = Some(password.into());
+        self
+    }
+
+    /// Reads the cookie from `path` instead of the file Tor advertises.
+    pub fn cookie_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.cookie_file = Some(path.into());
+        self
+    }
+
+    /// The control port endpoint.
+    pub fn endpoint(&self) -> &LocalEndpoint {
+        &self.endpoint
+    }
+
+    /// Opens and authenticates a control connection.
+    pub async fn connect(&self) -> Result<ControlConnection, ControlError> {
+        let mut control = ControlConnection::connect(&self.endpoint).await?;
+        let info = control.protocol_info().await?;
+        let offers = |method| info.auth_methods.iter().any(|m| m == method);
+        let cookie_file = || {
+            self.cookie_file
+                .as_deref()
+                .or(info.cookie_file.as_deref())
+                .ok_or(ControlError::Protocol("no cookie file advertised"))
+        };
+
+        if offers("NULL") {
+            control.authenticate(None).await?;
+        } else if let (true, Some(password)) = (offers("HASHEDPASSWORD"), &self.password) {
+            control.authenticate(Some(&quote(password))).await?;
+        } else if offers("SAFECOOKIE") {
+            let cookie = read_cookie(cookie_file()?)?;
+            control.safecookie(&cookie).await?;
+        } else if offers("COOKIE") {
+            let cookie = read_cookie(cookie_file()?)?;
+            control
+                .authenticate(Some(&HEXUPPER.encode(&cookie)))
+                .await?;
+        } else {
+            return Err(ControlError::NoAuthMethod(info.auth_methods));
+        }
+
+        Ok(control)
+    }
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        ControlConfig::new(LocalEndpoint::localhost(DEFAULT_CONTROL_PORT))
+    }
+}
+
+/// Tor's bootstrap progress, from `GETINFO status/bootstrap-phase`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootstrapPhase {
+    /// Percentage done, 100 once Tor can build circuits.
+    pub progress: u8,
+    /// Machine readable name of the phase.
+    pub tag: String,
+    /// Human readable description of the phase.
+    pub summary: String,
+}
+
+impl BootstrapPhase {
+    /// Whether Tor is fully bootstrapped.
+    pub fn is_done(&self) -> bool {
+        self.progress == 100
+    }
+
+    // Parses e.g. `NOTICE BOOTSTRAP PROGRESS=100 TAG=done SUMMARY="Done"`.
+    fn parse(status: &str) -> Result<Self, ControlError> {
+        let progress = keyword(status, "PROGRESS")
+            .and_then(|p| p.parse().ok())
+            .ok_or(ControlError::Protocol("invalid bootstrap phase"))?;
+        let tag = keyword(status, "TAG").unwrap_or_default().to_owned();
+        let summary = match status.find("SUMMARY=") {
+            Some(i) => {
+                unquote(&status[i + "SUMMARY=".len()..])
+                    .ok_or(ControlError::Protocol("invalid bootstrap phase"))?
+                    .0
+            }
+            None => String::new(),
+        };
+
+        Ok(BootstrapPhase {
+            progress,
+            tag,
+            summary,
+        })
+    }
+}
+
+impl fmt::Display for BootstrapPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}% ({})", self.progress, self.summary)
+    }
+}
+
+/// An `HS_DESC` event, reporting on onion service descriptor fetches and
+/// uploads.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HsDescEvent {
+    /// What happened, e.g. UPLOAD, UPLOADED or FAILED.
+    pub action: String,
+    /// The onion address without the `.onion` suffix.
+    pub address: String,
+    /// The HSDir involved.
+    pub hsdir: String,
+    /// Why the action failed, if it did.
+    pub reason: Option<String>,
+}
+
+impl HsDescEvent {
+    // Parses e.g. `HS_DESC UPLOADED <address> UNKNOWN <hsdir> <descid>`,
+    // `None` for other events.
+    fn parse(reply: &Reply) -> Option<Self> {
+        let line = reply.lines.first()?.strip_prefix("HS_DESC ")?;
+        let mut fields = line.split(' ');
+        let action = fields.next()?.to_owned();
+        let address = fields.next()?.to_owned();
+        let _auth_type = fields.next()?;
+        let hsdir = fields.next()?.to_owned();
+
+        Some(HsDescEvent {
+            action,
+            address,
+            hsdir,
+            reason: keyword(line, "REASON").map(str::to_owned),
+        })
+    }
+}
+
+/// The parts of a `PROTOCOLINFO` reply we use.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolInfo {
+    /// Authentication methods Tor accepts.
+    pub auth_methods: Vec<String>,
+    /// The cookie file for COOKIE and SAFECOOKIE authentication.
+    pub cookie_file: Option<PathBuf>,
+    /// Tor's version.
+    pub tor_version: Option<String>,
+}
+
+impl ProtocolInfo {
+    fn parse(reply: &Reply) -> Result<Self, ControlError> {
+        let mut info = ProtocolInfo {
+            auth_methods: Vec::new(),
+            cookie_file: None,
+            tor_version: None,
+        };
+        for line in &reply.lines {
+            if let Some(auth) = line.strip_prefix("AUTH ") {
+                let methods = keyword(auth, "METHODS")
+                    .ok_or(ControlError::Protocol("PROTOCOLINFO without auth methods"))?;
+                info.auth_methods = methods.split(',').map(str::to_owned).collect();
+                if let Some(i) = auth.find("COOKIEFILE=") {
+                    let (path, _) = unquote(&auth[i + "COOKIEFILE=".len()..])
+                        .ok_or(ControlError::Protocol("invalid COOKIEFILE"))?;
+                    info.cookie_file = Some(PathBuf::from(path));
+                }
+            } else if let Some(version) = line.strip_prefix("VERSION Tor=") {
+                info.tor_version = unquote(version).map(|(v, _)| v);
+            }
+        }
+        Ok(info)
+    }
+}
+
+/// The value of `key` in a line of space separated `key=value` pairs.
+fn keyword<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    line.split(' ').find_map(|pair| {
+        let value = pair.strip_prefix(key)?.strip_prefix('=')?;
+        Some(value)
+    })
+}
+
+/// Parses a quoted string at the start of `s`, returns it and the rest of `s`.
+fn unquote(s: &str) -> Option<(String, &str)> {
+    let mut out = String::new();
+    let mut chars = s.strip_prefix('"')?.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((out, &s[i + 2..])),
+            '\\' => out.push(chars.next()?.1),
+            c => out.push(c),
+        }
+    }
+    None
+}
+
+/// Quotes `s` for use as a command argument.
+fn quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        if c == '"' || c == '\\' {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out.push('"');
+    out
+}
+
+fn read_cookie(path: &Path) -> Result<Vec<u8>, ControlError> {
+    let cookie = fs::read(path).map_err(ControlError::Cookie)?;
+    if cookie.len() != COOKIE_LEN {
+        return Err(ControlError::Protocol("cookie file has wrong length"));
+    }
+    Ok(cookie)
+}
+
+fn safecookie_hmac(key: &[u8], cookie: &[u8], client_nonce: &[u8], server_nonce: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC accepts keys of any length");
+    mac.input(cookie);
+    mac.input(client_nonce);
+    mac.input(server_nonce);
+    mac.result().code().to_vec()
+}
+
+/// A reply to a control command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reply {
+    /// Three digit status code.
+    pub status: u16,
+    /// Reply lines without status code and separator, data lines are
+    /// appended to their line separated by `\n`.
+    pub lines: Vec<String>,
+}
+
+impl Reply {
+    /// The value of the first `key=value` line with `key`.
+    pub fn value(&self, key: &str) -> Option<&str> {
+        self.lines.iter().find_map(|line| {
+            let (k, v) = line.split_at(line.find('=')?);
+            if k == key {
+                Some(&v[1..])
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// Errors talking to the control port.
+#[derive(Debug)]
+pub enum ControlError {
+    /// I/O error on the control connection.
+    Io(io::Error),
+    /// Tor sent something we don't understand.
+    Protocol(&'static str),
+    /// Tor rejected a command.
+    Rejected(Reply),
+    /// None of the offered authentication methods is usable.
+    NoAuthMethod(Vec<String>),
+    /// Reading the authentication cookie failed.
+    Cookie(io::Error),
+    /// Tor did not prove knowledge of the cookie during SAFECOOKIE.
+    ServerHashMismatch,
+    /// Tor did not finish bootstrapping in time, with the last phase seen
+    /// if any.
+    BootstrapTimeout(Option<BootstrapPhase>),
+    /// No onion service descriptor was uploaded in time.
+    PublishTimeout {
+        /// Number of failed uploads.
+        failures: usize,
+    },
+}
+
+impl fmt::Display for ControlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlError::Io(e) => write!(f, "control port I/O error: {}", e),
+            ControlError::Protocol(msg) => write!(f, "control protocol error: {}", msg),
+            ControlError::Rejected(reply) => write!(
+                f,
+                "control command failed: {} {}",
+                reply.status,
+                reply.lines.join(" ")
+            ),
+            ControlError::NoAuthMethod(offered) => write!(
+                f,
+                "no usable control port authentication method, Tor offers: {}",
+                offered.join(",")
+            ),
+            ControlError::Cookie(e) => write!(f, "failed to read control auth cookie: {}", e),
+            ControlError::ServerHashMismatch => {
+                write!(f, "SAFECOOKIE server hash mismatch, is this really Tor?")
+            }
+            ControlError::BootstrapTimeout(Some(phase)) => {
+                write!(
+                    f,
+                    "timed out waiting for Tor to bootstrap, stuck at {}",
+                    phase
+                )
+            }
+            ControlError::BootstrapTimeout(None) => {
+                write!(f, "timed out waiting for Tor's bootstrap phase")
+            }
+            ControlError::PublishTimeout { failures } => write!(
+                f,
+                "timed out waiting for onion descriptor upload, {} uploads failed",
+                failures
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ControlError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ControlError::Io(e) | ControlError::Cookie(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ControlError {
+    fn from(e: io::Error) -> Self {
+        ControlError::Io(e)
+    }
+}
+
+impl From<ControlError> for io::Error {
+    fn from(e: ControlError) -> Self {
+        let kind = match &e {
+            ControlError::Io(e) | ControlError::Cookie(e) => e.kind(),
+            ControlError::Protocol(_) => io::ErrorKind::InvalidData,
+            ControlError::Rejected(_) => io::ErrorKind::Other,
+            ControlError::BootstrapTimeout(_) | ControlError::PublishTimeout { .. } => {
+                io::ErrorKind::TimedOut
+            }
+            ControlError::NoAuthMethod(_) | ControlError::ServerHashMismatch => {
+                io::ErrorKind::PermissionDenied
+            }
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+/// Key of an onion service created with `ADD_ONION`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OnionKey {
+    /// Let Tor generate a new ED25519-V3 key.
+    New,
+    /// An existing key, of form: ED25519-V3:BASE64
+    Existing(String),
+}
+
+impl OnionKey {
+    /// Uses the libp2p identity `keypair` as the onion service key, so that
+    /// the onion address is derived from our `PeerId`. `None` unless
+    /// `keypair` is an ed25519 key.
+    pub fn from_identity(keypair: &identity::Keypair) -> Option<Self> {
+        let keypair = match keypair {
+            identity::Keypair::Ed25519(keypair) => keypair,
+            _ => return None,
+        };
+        let mut secret = [0u8; 32];
+        secret.copy_from_slice(keypair.secret().as_ref());
+        let expanded = expand_secret_key(&secret);
+        Some(OnionKey::Existing(format!(
+            "ED25519-V3:{}",
+            BASE64.encode(&expanded)
+        )))
+    }
+}
+
+impl fmt::Display for OnionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OnionKey::New => write!(f, "NEW:ED25519-V3"),
+            OnionKey::Existing(key) => write!(f, "{}", key),
+        }
+    }
+}
+
+/// Parameters of an `ADD_ONION` command.
+#[derive(Debug, Clone)]
+pub struct AddOnion {
+    key: OnionKey,
+    ports: Vec<(u16, SocketAddr)>,
+    discard_key: bool,
+    authorized_clients: Vec<ClientAuthPublicKey>,
+}
+
+impl AddOnion {
+    /// Creates an onion service with `key`, without any ports.
+    pub fn new(key: OnionKey) -> Self {
+        AddOnion {
+            key,
+            ports: Vec::new(),
+            discard_key: false,
+            authorized_clients: Vec::new(),
+        }
+    }
+
+    /// Forwards connections to virtual port `port` to `target`.
+    pub fn port(mut self, port: u16, target: SocketAddr) -> Self {
+        self.ports.push((port, target));
+        self
+    }
+
+    /// Don't have Tor return a newly generated key.
+    pub fn discard_key(mut self, discard: bool) -> Self {
+        self.discard_key = discard;
+        self
+    }
+
+    /// Only lets the client with the private key for `key` connect, may
+    /// be given several times.
+    pub fn authorized_client(mut self, key: ClientAuthPublicKey) -> Self {
+        self.authorized_clients.push(key);
+        self
+    }
+
+    fn command(&self) -> String {
+        let mut cmd = format!("ADD_ONION {}", self.key);
+        if self.discard_key {
+            cmd.push_str(" Flags=DiscardPK");
+        }
+        for (port, target) in &self.ports {
+            cmd.push_str(&format!(" Port={},{}", port, target));
+        }
+        for key in &self.authorized_clients {
+            cmd.push_str(&format!(" ClientAuthV3={}", key));
+        }
+        cmd
+    }
+}
+
+/// An onion service created with `ADD_ONION`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OnionService {
+    /// The onion address without the `.onion` suffix.
+    pub service_id: String,
+    /// Key generated by Tor, if we asked for a new one and did not discard it.
+    pub private_key: Option<String>,
+}
+
+impl OnionService {
+    /// The `/onion3` multiaddr of this service for virtual port `port`.
+    pub fn multiaddr(&self, port: u16) -> Result<Multiaddr, ControlError> {
+        let addr = OnionAddress::from_str(&format!("{}:{}", self.service_id, port))
+            .map_err(|_| ControlError::Protocol("invalid ServiceID"))?;
+        Ok(Multiaddr::empty().with(addr.to_protocol()))
+    }
+}
+
+/// An open control connection.
+#[derive(Debug)]
+pub struct ControlConnection<S = LocalStream> {
+    stream: BufReader<S>,
+    /// Asynchronous events received while waiting for a command's reply.
+    events: VecDeque<Reply>,
+}
+
+impl ControlConnection {
+    /// Opens an unauthenticated connection to the control port at `endpoint`.
+    pub async fn connect(endpoint: &LocalEndpoint) -> Result<Self, ControlError> {
+        Ok(ControlConnection::new(endpoint.connect().await?))
+    }
+}
+
+impl<S> ControlConnection<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Wraps an already connected stream.
+    pub fn new(stream: S) -> Self {
+        ControlConnection {
+            stream: BufReader::new(stream),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Asks Tor which authentication methods it accepts, only allowed
+    /// once before authenticating.
+    pub async fn protocol_info(&mut self) -> Result<ProtocolInfo, ControlError> {
+        let reply = self.command("PROTOCOLINFO 1").await?;
+        ProtocolInfo::parse(&reply)
+    }
+
+    /// Sends `AUTHENTICATE` with an already encoded `secret`, if any.
+    pub async fn authenticate(&mut self, secret: Option<&str>) -> Result<(), ControlError> {
+        let cmd = match secret {
+            Some(secret) => format!("AUTHENTICATE {}", secret),
+            None => "AUTHENTICATE".to_owned(),
+        };
+        self.command(&cmd).await.map(drop)
+    }
+
+    /// Authenticates with the SAFECOOKIE challenge-response method.
+    pub async fn safecookie(&mut self, cookie: &[u8]) -> Result<(), ControlError> {
+        let mut client_nonce = [0u8; COOKIE_LEN];
+        rand::thread_rng().fill_bytes(&mut client_nonce);
+
+        let cmd = format!(
+            "AUTHCHALLENGE SAFECOOKIE {}",
+            HEXUPPER.encode(&client_nonce)
+        );
+        let reply = self.command(&cmd).await?;
+        let line = reply
+            .lines
+            .first()
+            .and_then(|l| l.strip_prefix("AUTHCHALLENGE "))
+            .ok_or(ControlError::Protocol("invalid AUTHCHALLENGE reply"))?;
+        let decode = |key| {
+            keyword(line, key)
+                .and_then(|hex| HEXLOWER_PERMISSIVE.decode(hex.as_bytes()).ok())
+                .ok_or(ControlError::Protocol("invalid AUTHCHALLENGE reply"))
+        };
+        let server_hash = decode("SERVERHASH")?;
+        let server_nonce = decode("SERVERNONCE")?;
+
+        let want = safecookie_hmac(SAFECOOKIE_SERVER_KEY, cookie, &client_nonce, &server_nonce);
+        if server_hash != want {
+            return Err(ControlError::ServerHashMismatch);
+        }
+
+        let client_hash =
+            safecookie_hmac(SAFECOOKIE_CLIENT_KEY, cookie, &client_nonce, &server_nonce);
+        self.authenticate(Some(&HEXUPPER.encode(&client_hash)))
+            .await
+    }
+
+    /// Creates an ephemeral onion service, it is removed when this
+    /// connection is closed.
+    pub async fn add_onion(&mut self, request: &AddOnion) -> Result<OnionService, ControlError> {
+        let reply = self.command(&request.command()).await?;
+        let service_id = reply
+            .value("ServiceID")
+            .ok_or(ControlError::Protocol("ADD_ONION reply without ServiceID"))?;
+
+        Ok(OnionService {
+            service_id: service_id.to_owned(),
+            private_key: reply.value("PrivateKey").map(str::to_owned),
+        })
+    }
+
+    /// Removes an onion service created on this connection.
+    pub async fn del_onion(&mut self, service_id: &str) -> Result<(), ControlError> {
+        self.command(&format!("DEL_ONION {}", service_id))
+            .await
+            .map(drop)
+    }
+
+    /// Registers our client authorization key for the onion service
+    /// `service_id`, until Tor exits.
+    pub async fn onion_client_auth_add(
+        &mut self,
+        service_id: &str,
+        key: &ClientAuthKey,
+    ) -> Result<(), ControlError> {
+        let cmd = format!(
+            "ONION_CLIENT_AUTH_ADD {} x25519:{}",
+            service_id,
+            key.to_base64()
+        );
+        self.command(&cmd).await.map(drop)
+    }
+
+    /// Queries a single value with `GETINFO`.
+    pub async fn get_info(&mut self, key: &str) -> Result<String, ControlError> {
+        let reply = self.command(&format!("GETINFO {}", key)).await?;
+        reply
+            .value(key)
+            .map(str::to_owned)
+            .ok_or(ControlError::Protocol(
+                "GETINFO reply without requested key",
+            ))
+    }
+
+    /// Tor's current bootstrap phase.
+    pub async fn bootstrap_phase(&mut self) -> Result<BootstrapPhase, ControlError> {
+        let status = self.get_info("status/bootstrap-phase").await?;
+        BootstrapPhase::parse(&status)
+    }
+
+    /// Waits until Tor is fully bootstrapped, logging its progress. Gives up
+    /// after `timeout`.
+    pub async fn wait_for_bootstrap(&mut self, timeout: Duration) -> Result<(), ControlError> {
+        let mut last: Option<BootstrapPhase> = None;
+        let wait = async {
+            loop {
+                let phase = self.bootstrap_phase().await?;
+                if last.as_ref().map(|last| last.progress) != Some(phase.progress) {
+                    info!("Tor bootstrapped {}", phase);
+                }
+                let done = phase.is_done();
+                last = Some(phase);
+                if done {
+                    return Ok(());
+                }
+                Delay::new(BOOTSTRAP_POLL_INTERVAL).await;
+            }
+        };
+        let res = tokio::time::timeout(timeout, wait).await;
+        match res {
+            Ok(res) => res,
+            Err(_) => Err(ControlError::BootstrapTimeout(last)),
+        }
+    }
+
+    /// Sends `command` and reads the reply, failing unless the status is 250.
+    pub async fn command(&mut self, command: &str) -> Result<Reply, ControlError> {
+        let stream = self.stream.get_mut();
+        stream.write_all(command.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?;
+        stream.flush().await?;
+
+        let reply = loop {
+            let reply = self.read_reply().await?;
+            if reply.status != STATUS_EVENT {
+                break reply;
+            }
+            self.events.push_back(reply);
+        };
+        if reply.status != STATUS_OK {
+            return Err(ControlError::Rejected(reply));
+        }
+        Ok(reply)
+    }
+
+    /// Subscribes to `events`, replacing any previous subscription.
+    pub async fn set_events(&mut self, events: &[&str]) -> Result<(), ControlError> {
+        let mut cmd = "SETEVENTS".to_owned();
+        for event in events {
+            cmd.push(' ');
+            cmd.push_str(event);
+        }
+        self.command(&cmd).await.map(drop)
+    }
+
+    /// Waits for the next asynchronous event.
+    pub async fn next_event(&mut self) -> Result<Reply, ControlError> {
+        if let Some(event) = self.events.pop_front() {
+            return Ok(event);
+        }
+        let reply = self.read_reply().await?;
+        if reply.status != STATUS_EVENT {
+            return Err(ControlError::Protocol(
+                "unexpected reply while waiting for event",
+            ));
+        }
+        Ok(reply)
+    }
+
+    /// Waits until the descriptor of the onion service `service_id` has been
+    /// uploaded to at least one HSDir, logging failed uploads. Requires a
+    /// subscription to `HS_DESC` events made before creating the service.
+    pub async fn wait_for_upload(
+        &mut self,
+        service_id: &str,
+        timeout: Duration,
+    ) -> Result<HsDescEvent, ControlError> {
+        let mut failures = 0;
+        let wait = async {
+            loop {
+                let reply = self.next_event().await?;
+                let event = match HsDescEvent::parse(&reply) {
+                    Some(event) if event.address == service_id => event,
+                    _ => continue,
+                };
+                match event.action.as_str() {
+                    "UPLOADED" => return Ok(event),
+                    "FAILED" => {
+                        failures += 1;
+                        warn!(
+                            "onion descriptor upload to {} failed: {}",
+                            event.hsdir,
+                            event.reason.as_deref().unwrap_or("unknown reason")
+                        );
+                    }
+                    _ => debug!("onion descriptor {} {}", event.action, event.hsdir),
+                }
+            }
+        };
+        let res = tokio::time::timeout(timeout, wait).await;
+        match res {
+            Ok(res) => res,
+            Err(_) => Err(ControlError::PublishTimeout { failures }),
+        }
+    }
+
+    async fn read_reply(&mut self) -> Result<Reply, ControlError> {
+        let mut status = None;
+        let mut lines = Vec::new();
+        loop {
+            let line = self.read_line().await?;
+            // Whatever listens on the control port may send anything, only
+            // index bytes until we know the line starts with ASCII.
+            let (code, separator) = match line.as_bytes() {
+                [a, b, c, separator, ..] if [a, b, c].iter().all(|d| d.is_ascii_digit()) => {
+                    let code = [a, b, c]
+                        .iter()
+                        .fold(0, |code, d| code * 10 + u16::from(**d - b'0'));
+                    (code, *separator)
+                }
+                [_, _, _, _, ..] => return Err(ControlError::Protocol("invalid status code")),
+                _ => return Err(ControlError::Protocol("reply line too short")),
+            };
+            if *status.get_or_insert(code) != code {
+                return Err(ControlError::Protocol("status code changed within reply"));
+            }
+
+            let mut text = line.get(4..).unwrap_or_default().to_owned();
+            match separator {
+                b' ' => {
+                    lines.push(text);
+                    return Ok(Reply {
+                        status: code,
+                        lines,
+                    });
+                }
+                b'-' => lines.push(text),
+                b'+' => {
+                    loop {
+                        let data = self.read_line().await?;
+                        if data == "." {
+                            break;
+                        }
+                        text.push('\n');
+                        text.push_str(data.strip_prefix('.').unwrap_or(&data));
+                    }
+                    lines.push(text);
+                }
+                _ => return Err(ControlError::Protocol("invalid reply separator")),
+            }
+        }
+    }
+
+    async fn read_line(&mut self) -> Result<String, ControlError> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            return Err(ControlError::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+        if !line.ends_with("\r\n") {
+            return Err(ControlError::Protocol("reply line not terminated by CRLF"));
+        }
+        line.truncate(line.len() - 2);
+        Ok(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    const SERVICE_ID: &str = "vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd";
+
+    /// Runs a fake control server that answers each command line with the
+    /// next entry of `replies`, returns the commands it received.
+    async fn fake_server(replies: &[&str]) -> (SocketAddr, tokio::task::JoinHandle<String>) {
+        let replies: Vec<String> = replies.iter().map(|r| r.to_string()).collect();
+        let any: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let mut listener = tokio::net::TcpListener::bind(&any).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (sock, _) = listener.accept().await.unwrap();
+            let mut sock = BufReader::new(sock);
+            let mut received = String::new();
+            for reply in replies {
+                sock.read_line(&mut received).await.unwrap();
+                sock.get_mut().write_all(reply.as_bytes()).await.unwrap();
+            }
+            // Wait for the client to hang up.
+            let _ = sock.read_to_string(&mut received).await;
+            received
+        });
+
+        (addr, server)
+    }
+
+    #[test]
+    fn add_onion_command_maps_ports() {
+        let target: SocketAddr = "127.0.0.1:7777".parse().unwrap();
+        let cmd = AddOnion::new(OnionKey::New)
+            .discard_key(true)
+            .port(7, target)
+            .command();
+
+        assert_eq!(
+            cmd,
+            "ADD_ONION NEW:ED25519-V3 Flags=DiscardPK Port=7,127.0.0.1:7777"
+        );
+    }
+
+    #[test]
+    fn add_onion_command_lists_authorized_clients() {
+        let key = ClientAuthKey::from_bytes([1; 32]).public_key();
+        let cmd = AddOnion::new(OnionKey::New)
+            .authorized_client(key)
+            .command();
+
+        assert_eq!(
+            cmd,
+            format!("ADD_ONION NEW:ED25519-V3 ClientAuthV3={}", key)
+        );
+    }
+
+    #[test]
+    fn service_id_converts_to_multiaddr() {
+        let service = OnionService {
+            service_id: SERVICE_ID.to_owned(),
+            private_key: None,
+        };
+        let want: Multiaddr = format!("/onion3/{}:7", SERVICE_ID).parse().unwrap();
+
+        assert_eq!(service.multiaddr(7).unwrap(), want);
+    }
+
+    #[tokio::test]
+    async fn can_add_and_remove_onion_service() {
+        let add_reply = format!(
+            "250-ServiceID={}\r\n250-PrivateKey=ED25519-V3:c2VjcmV0\r\n250 OK\r\n",
+            SERVICE_ID
+        );
+        let (addr, server) = fake_server(&["250 OK\r\n", &add_reply, "250 OK\r\n"]).await;
+
+        let mut control = ControlConnection::connect(&LocalEndpoint::Tcp(addr))
+            .await
+            .unwrap();
+        control.authenticate(None).await.unwrap();
+        let target: SocketAddr = "127.0.0.1:7777".parse().unwrap();
+        let service = control
+            .add_onion(&AddOnion::new(OnionKey::New).port(7, target))
+            .await
+            .unwrap();
+        control.del_onion(&service.service_id).await.unwrap();
+        drop(control);
+
+        assert_eq!(service.service_id, SERVICE_ID);
+        assert_eq!(service.private_key.as_deref(), Some("ED25519-V3:c2VjcmV0"));
+        let received = server.await.unwrap();
+        assert_eq!(
+            received,
+            format!(
+                "AUTHENTICATE\r\nADD_ONION NEW:ED25519-V3 Port=7,127.0.0.1:7777\r\nDEL_ONION {}\r\n",
+                SERVICE_ID
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_rejected_command() {
+        let (addr, _server) = fake_server(&["552 Unrecognized command \"FOO\"\r\n"]).await;
+
+        let mut control = ControlConnection::connect(&LocalEndpoint::Tcp(addr))
+            .await
+            .unwrap();
+        match control.command("FOO").await {
+            Err(ControlError::Rejected(reply)) => assert_eq!(reply.status, 552),
+            other => panic!("expected rejection, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_reply_lines() {
+        for reply in &["25\r\n", "2\u{e9}0 OK\r\n", "250\u{e9}OK\r\n", "abc OK\r\n"] {
+            let (addr, _server) = fake_server(&[reply]).await;
+
+            let mut control = ControlConnection::connect(&LocalEndpoint::Tcp(addr))
+                .await
+                .unwrap();
+            match control.command("GETINFO version").await {
+                Err(ControlError::Protocol(_)) => {}
+                other => panic!("expected protocol error for {:?}, got {:?}", reply, other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn can_read_data_reply() {
+        let (addr, _server) =
+            fake_server(&["250+config-text=\r\nSocksPort 9050\r\n..\r\n.\r\n250 OK\r\n"]).await;
+
+        let mut control = ControlConnection::connect(&LocalEndpoint::Tcp(addr))
+            .await
+            .unwrap();
+        let reply = control.command("GETINFO config-text").await.unwrap();
+
+        assert_eq!(reply.value("config-text"), Some("\nSocksPort 9050\n."));
+    }
+
+    const PROTOCOLINFO_COOKIE: &str = "250-PROTOCOLINFO 1\r\n250-AUTH METHODS=COOKIE,SAFECOOKIE COOKIEFILE=\"/run/tor/control.authcookie\"\r\n250-VERSION Tor=\"0.4.3.5\"\r\n250 OK\r\n";
+
+    fn write_cookie(dir: &Path) -> (PathBuf, [u8; COOKIE_LEN]) {
+        let path = dir.join("control_auth_cookie");
+        let cookie = [0x2a; COOKIE_LEN];
+        fs::write(&path, cookie).unwrap();
+        (path, cookie)
+    }
+
+    #[test]
+    fn can_parse_protocol_info() {
+        let reply = Reply {
+            status: STATUS_OK,
+            lines: vec![
+                "PROTOCOLINFO 1".to_owned(),
+                r#"AUTH METHODS=COOKIE,SAFECOOKIE COOKIEFILE="/run/tor/control \"auth\" cookie""#
+                    .to_owned(),
+                r#"VERSION Tor="0.4.3.5""#.to_owned(),
+                "OK".to_owned(),
+            ],
+        };
+        let info = ProtocolInfo::parse(&reply).unwrap();
+
+        assert_eq!(info.auth_methods, vec!["COOKIE", "SAFECOOKIE"]);
+        assert_eq!(
+            info.cookie_file,
+            Some(PathBuf::from(r#"/run/tor/control "auth" cookie"#))
+        );
+        assert_eq!(info.tor_version.as_deref(), Some("0.4.3.5"));
+    }
+
+    #[tokio::test]
+    async fn authenticates_with_password() {
+        let (addr, server) = fake_server(&[
+            "250-PROTOCOLINFO 1\r\n250-AUTH METHODS=HASHEDPASSWORD\r\n250 OK\r\n",
+            "250 OK\r\n",
+        ])
+        .await;
+
+        let config = ControlConfig::new(LocalEndpoint::Tcp(addr)).password(r#"pa"ss"#);
+        drop(config.connect().await.unwrap());
+
+        let received = server.await.unwrap();
+        assert_eq!(received, "PROTOCOLINFO 1\r\nAUTHENTICATE \"pa\\\"ss\"\r\n");
+    }
+
+    #[tokio::test]
+    async fn authenticates_with_cookie() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, cookie) = write_cookie(dir.path());
+        let (addr, server) = fake_server(&[
+            "250-PROTOCOLINFO 1\r\n250-AUTH METHODS=COOKIE COOKIEFILE=\"/nonexistent\"\r\n250 OK\r\n",
+            "250 OK\r\n",
+        ])
+        .await;
+
+        let config = ControlConfig::new(LocalEndpoint::Tcp(addr)).cookie_file(&path);
+        drop(config.connect().await.unwrap());
+
+        let received = server.await.unwrap();
+        assert_eq!(
+            received,
+            format!(
+                "PROTOCOLINFO 1\r\nAUTHENTICATE {}\r\n",
+                HEXUPPER.encode(&cookie)
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn authenticates_with_safecookie_via_unix_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cookie_path, cookie) = write_cookie(dir.path());
+        let socket = dir.path().join("control");
+        let mut listener = tokio::net::UnixListener::bind(&socket).unwrap();
+
+        let server = tokio::spawn(async move {
+            let (sock, _) = listener.accept().await.unwrap();
+            let mut sock = BufReader::new(sock);
+            let mut line = String::new();
+
+            sock.read_line(&mut line).await.unwrap();
+            sock.get_mut()
+                .write_all(PROTOCOLINFO_COOKIE.as_bytes())
+                .await
+                .unwrap();
+
+            line.clear();
+            sock.read_line(&mut line).await.unwrap();
+            let client_nonce = line
+                .trim_end()
+                .strip_prefix("AUTHCHALLENGE SAFECOOKIE ")
+                .map(|hex| HEXUPPER.decode(hex.as_bytes()).unwrap())
+                .unwrap();
+            let server_nonce = [0x17; COOKIE_LEN];
+            let server_hash =
+                safecookie_hmac(SAFECOOKIE_SERVER_KEY, &cookie, &client_nonce, &server_nonce);
+            let reply = format!(
+                "250 AUTHCHALLENGE SERVERHASH={} SERVERNONCE={}\r\n",
+                HEXUPPER.encode(&server_hash),
+                HEXUPPER.encode(&server_nonce)
+            );
+            sock.get_mut().write_all(reply.as_bytes()).await.unwrap();
+
+            line.clear();
+            sock.read_line(&mut line).await.unwrap();
+            let client_hash =
+                safecookie_hmac(SAFECOOKIE_CLIENT_KEY, &cookie, &client_nonce, &server_nonce);
+            let ok = line.trim_end() == format!("AUTHENTICATE {}", HEXUPPER.encode(&client_hash));
+            let reply: &[u8] = if ok {
+                b"250 OK\r\n"
+            } else {
+                b"515 Authentication failed\r\n"
+            };
+            sock.get_mut().write_all(reply).await.unwrap();
+        });
+
+        let config =
+            ControlConfig::new(LocalEndpoint::Unix(socket.clone())).cookie_file(&cookie_path);
+        let res = config.connect().await;
+        server.await.unwrap();
+
+        res.expect("SAFECOOKIE authentication failed");
+    }
+
+    #[tokio::test]
+    async fn reports_missing_auth_method() {
+        let (addr, _server) =
+            fake_server(&["250-PROTOCOLINFO 1\r\n250-AUTH METHODS=HASHEDPASSWORD\r\n250 OK\r\n"])
+                .await;
+
+        match ControlConfig::new(LocalEndpoint::Tcp(addr)).connect().await {
+            Err(ControlError::NoAuthMethod(offered)) => assert_eq!(offered, vec!["HASHEDPASSWORD"]),
+            other => panic!("expected NoAuthMethod, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn can_parse_bootstrap_phase() {
+        let phase = BootstrapPhase::parse(
+            r#"NOTICE BOOTSTRAP PROGRESS=85 TAG=ap_handshake_done SUMMARY="Handshake finished with a relay to build circuits""#,
+        )
+        .unwrap();
+
+        assert_eq!(phase.progress, 85);
+        assert_eq!(phase.tag, "ap_handshake_done");
+        assert_eq!(
+            phase.summary,
+            "Handshake finished with a relay to build circuits"
+        );
+        assert!(!phase.is_done());
+    }
+
+    #[tokio::test]
+    async fn waits_until_bootstrapped() {
+        let (addr, server) = fake_server(&[
+            "250-status/bootstrap-phase=NOTICE BOOTSTRAP PROGRESS=50 TAG=loading_descriptors SUMMARY=\"Loading relay descriptors\"\r\n250 OK\r\n",
+            "250-status/bootstrap-phase=NOTICE BOOTSTRAP PROGRESS=100 TAG=done SUMMARY=\"Done\"\r\n250 OK\r\n",
+        ])
+        .await;
+
+        let mut control = ControlConnection::connect(&LocalEndpoint::Tcp(addr))
+            .await
+            .unwrap();
+        control
+            .wait_for_bootstrap(Duration::from_secs(5))
+            .await
+            .unwrap();
+        drop(control);
+
+        let received = server.await.unwrap();
+        assert_eq!(received, "GETINFO status/bootstrap-phase\r\n".repeat(2));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_bootstrap_timeout() {
+        let (addr, _server) = fake_server(&[
+            "250-status/bootstrap-phase=NOTICE BOOTSTRAP PROGRESS=10 TAG=conn_done SUMMARY=\"Connected to a relay\"\r\n250 OK\r\n",
+        ])
+        .await;
+
+        let mut control = ControlConnection::connect(&LocalEndpoint::Tcp(addr))
+            .await
+            .unwrap();
+        match control.wait_for_bootstrap(Duration::from_millis(100)).await {
+            Err(ControlError::BootstrapTimeout(Some(phase))) => assert_eq!(phase.progress, 10),
+            other => panic!("expected BootstrapTimeout, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn waits_for_descriptor_upload() {
+        let (addr, server) = fake_server(&[
+            "250 OK\r\n",
+            // Events may arrive while we wait for a command's reply.
+            &format!(
+                "650 HS_DESC UPLOAD {id} UNKNOWN $AAAA descid\r\n\
+                 650 HS_DESC FAILED {id} UNKNOWN $AAAA descid REASON=UPLOAD_REJECTED\r\n\
+                 250 OK\r\n\
+                 650 HS_DESC UPLOADED {id} UNKNOWN $BBBB\r\n",
+                id = SERVICE_ID
+            ),
+        ])
+        .await;
+
+        let mut control = ControlConnection::connect(&LocalEndpoint::Tcp(addr))
+            .await
+            .unwrap();
+        control.set_events(&["HS_DESC"]).await.unwrap();
+        control.command("SIGNAL NEWNYM").await.unwrap();
+        let event = control
+            .wait_for_upload(SERVICE_ID, Duration::from_secs(5))
+            .await
+            .unwrap();
+        drop(control);
+
+        assert_eq!(event.hsdir, "$BBBB");
+        assert_eq!(
+            server.await.unwrap(),
+            "SETEVENTS HS_DESC\r\nSIGNAL NEWNYM\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_failed_descriptor_uploads_on_timeout() {
+        let (addr, _server) = fake_server(&[&format!(
+            "250 OK\r\n650 HS_DESC FAILED {} UNKNOWN $AAAA descid REASON=UPLOAD_REJECTED\r\n",
+            SERVICE_ID
+        )])
+        .await;
+
+        let mut control = ControlConnection::connect(&LocalEndpoint::Tcp(addr))
+            .await
+            .unwrap();
+        control.set_events(&["HS_DESC"]).await.unwrap();
+        match control
+            .wait_for_upload(SERVICE_ID, Duration::from_millis(200))
+            .await
+        {
+            Err(ControlError::PublishTimeout { failures }) => assert_eq!(failures, 1),
+            other => panic!("expected PublishTimeout, got {:?}", other),
+        }
+    }
+}
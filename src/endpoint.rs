@@ -0,0 +1,156 @@
+//! Local sockets Tor listens on, its SOCKS and control ports.
+//!
+//! Tor can expose either as a TCP socket or as a Unix-domain socket
+//! (`SocksPort unix:/run/tor/socks`), we support both.
+
+use std::{
+    fmt, io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::PathBuf,
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpStream, UnixStream},
+};
+
+/// Location of a local Tor port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LocalEndpoint {
+    /// Port listening on a TCP socket.
+    Tcp(SocketAddr),
+    /// Port listening on a Unix-domain socket.
+    Unix(PathBuf),
+}
+
+impl LocalEndpoint {
+    /// Port listening on localhost at `port`.
+    pub fn localhost(port: u16) -> Self {
+        LocalEndpoint::Tcp(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port)))
+    }
+
+    /// Opens a connection to the port.
+    pub async fn connect(&self) -> io::Result<LocalStream> {
+        match self {
+            LocalEndpoint::Tcp(addr) => TcpStream::connect(addr).await.map(LocalStream::Tcp),
+            LocalEndpoint::Unix(path) => UnixStream::connect(path).await.map(LocalStream::Unix),
+        }
+    }
+}
+
+impl fmt::Display for LocalEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LocalEndpoint::Tcp(addr) => write!(f, "{}", addr),
+            LocalEndpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Parses either `host:port` or `unix:/path/to/socket`, the same syntax
+/// used by the `SocksPort` and `ControlPort` options in torrc.
+impl FromStr for LocalEndpoint {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(LocalEndpoint::Unix(PathBuf::from(path)));
+        }
+        s.parse().map(LocalEndpoint::Tcp).map_err(|_| {
+            let msg = format!("invalid endpoint (want host:port or unix:/path): {}", s);
+            io::Error::new(io::ErrorKind::InvalidInput, msg)
+        })
+    }
+}
+
+/// Connection to a local Tor port.
+#[derive(Debug)]
+pub enum LocalStream {
+    /// Connection via TCP.
+    Tcp(TcpStream),
+    /// Connection via a Unix-domain socket.
+    Unix(UnixStream),
+}
+
+impl LocalStream {
+    /// The underlying TCP stream, if any.
+    pub fn as_tcp(&self) -> Option<&TcpStream> {
+        match self {
+            LocalStream::Tcp(stream) => Some(stream),
+            LocalStream::Unix(_) => None,
+        }
+    }
+}
+
+impl AsyncRead for LocalStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            LocalStream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            LocalStream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for LocalStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            LocalStream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            LocalStream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            LocalStream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            LocalStream::Unix(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            LocalStream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            LocalStream::Unix(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_parse_tcp_endpoint() {
+        let want = LocalEndpoint::localhost(9050);
+        let got: LocalEndpoint = "127.0.0.1:9050".parse().expect("failed to parse endpoint");
+
+        assert_eq!(got, want);
+        assert_eq!(got.to_string(), "127.0.0.1:9050");
+    }
+
+    #[test]
+    fn can_parse_unix_endpoint() {
+        let want = LocalEndpoint::Unix(PathBuf::from("/run/tor/socks"));
+        let got: LocalEndpoint = "unix:/run/tor/socks"
+            .parse()
+            .expect("failed to parse endpoint");
+
+        assert_eq!(got, want);
+        assert_eq!(got.to_string(), "unix:/run/tor/socks");
+    }
+
+    #[test]
+    fn rejects_invalid_endpoint() {
+        assert!("localhost".parse::<LocalEndpoint>().is_err());
+    }
+}
@@ -0,0 +1,301 @@
+//! Key files.
+//!
+//! Secret keys are written with mode 0600 and never overwritten.
+
+use std::{
+    fs::{self, DirBuilder, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use data_encoding::BASE64;
+use libp2p::core::identity::{self, ed25519};
+use log::info;
+
+use crate::{
+    control::OnionKey,
+    onion::{self, OnionAddress, PUBKEY_LEN},
+};
+
+const ED25519_V3_PREFIX: &str = "ED25519-V3:";
+const ED25519_V3_KEY_LEN: usize = 64;
+
+/// Length of the header of Tor's tagged key files, NUL padded.
+const TAG_LEN: usize = 32;
+const SECRET_KEY_TAG: &str = "== ed25519v1-secret: type0 ==";
+const PUBLIC_KEY_TAG: &str = "== ed25519v1-public: type0 ==";
+
+/// Reads an onion service key as returned by `ADD_ONION`, `None` if `path`
+/// does not exist.
+pub fn read_onion_key(path: &Path) -> io::Result<Option<OnionKey>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let key = contents.trim();
+
+    let valid = key
+        .strip_prefix(ED25519_V3_PREFIX)
+        .and_then(|blob| BASE64.decode(blob.as_bytes()).ok())
+        .is_some_and(|bytes| bytes.len() == ED25519_V3_KEY_LEN);
+    if !valid {
+        let msg = format!(
+            "{} does not contain an ED25519-V3 onion key",
+            path.display()
+        );
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    }
+
+    Ok(Some(OnionKey::Existing(key.to_owned())))
+}
+
+/// Where the swarm's libp2p identity comes from.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum IdentitySource {
+    /// A new identity on every start.
+    #[default]
+    Ephemeral,
+    /// Loaded from a file that must exist.
+    File(PathBuf),
+    /// Loaded from a file, or generated and saved there if the file does
+    /// not exist, so that our `PeerId` stays the same across restarts.
+    FileOrGenerate(PathBuf),
+}
+
+impl IdentitySource {
+    /// Loads or generates the identity.
+    pub fn keypair(&self) -> io::Result<identity::Keypair> {
+        let keypair = match self {
+            IdentitySource::Ephemeral => ed25519::Keypair::generate(),
+            IdentitySource::File(path) => read_identity(path)?.ok_or_else(|| {
+                let msg = format!("identity file not found: {}", path.display());
+                io::Error::new(io::ErrorKind::NotFound, msg)
+            })?,
+            IdentitySource::FileOrGenerate(path) => match read_identity(path)? {
+                Some(keypair) => keypair,
+                None => {
+                    let keypair = ed25519::Keypair::generate();
+                    write_identity(path, &keypair)?;
+                    info!("saved new identity to {}", path.display());
+                    keypair
+                }
+            },
+        };
+        Ok(identity::Keypair::Ed25519(keypair))
+    }
+}
+
+/// Reads a base64 encoded ed25519 secret key, `None` if `path` does not
+/// exist.
+pub fn read_identity(path: &Path) -> io::Result<Option<ed25519::Keypair>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let secret = BASE64
+        .decode(contents.trim().as_bytes())
+        .ok()
+        .and_then(|bytes| ed25519::SecretKey::from_bytes(bytes).ok());
+    match secret {
+        Some(secret) => Ok(Some(ed25519::Keypair::from(secret))),
+        None => {
+            let msg = format!(
+                "{} does not contain an ed25519 identity key",
+                path.display()
+            );
+            Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+        }
+    }
+}
+
+/// Saves the secret key of `keypair` base64 encoded.
+pub fn write_identity(path: &Path, keypair: &ed25519::Keypair) -> io::Result<()> {
+    let secret = BASE64.encode(keypair.secret().as_ref());
+    write_secret(path, format!("{}\n", secret).as_bytes())
+}
+
+/// Saves an onion service key in the form returned by `ADD_ONION`.
+pub fn write_onion_key(path: &Path, key: &str) -> io::Result<()> {
+    write_secret(path, format!("{}\n", key).as_bytes())
+}
+
+/// Writes the `HiddenServiceDir` for the onion service with key `keypair`,
+/// as Tor would: `hs_ed25519_secret_key`, `hs_ed25519_public_key` and
+/// `hostname`. Creates `dir` with mode 0700 if needed, but not its parents.
+/// An existing `dir` must not be accessible by other users, and existing
+/// files are not overwritten.
+///
+/// Returns the service's address with virtual port `port`.
+pub fn write_hidden_service_dir(
+    dir: &Path,
+    keypair: &ed25519::Keypair,
+    port: u16,
+) -> io::Result<OnionAddress> {
+    let mut secret = [0u8; PUBKEY_LEN];
+    secret.copy_from_slice(keypair.secret().as_ref());
+    let public_key = keypair.public().encode();
+    let address = OnionAddress::v3(public_key, port);
+
+    match DirBuilder::new().mode(0o700).create(dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => check_private_dir(dir)?,
+        Err(e) => return Err(e),
+    }
+    write_secret(
+        &dir.join("hs_ed25519_secret_key"),
+        &tagged(SECRET_KEY_TAG, &onion::expand_secret_key(&secret)),
+    )?;
+    write_secret(
+        &dir.join("hs_ed25519_public_key"),
+        &tagged(PUBLIC_KEY_TAG, &public_key),
+    )?;
+    write_secret(
+        &dir.join("hostname"),
+        format!("{}\n", address.host()).as_bytes(),
+    )?;
+
+    Ok(address)
+}
+
+// Fails unless `dir` is a directory only the current user can access, as
+// Tor refuses to use a `HiddenServiceDir` that others can read.
+fn check_private_dir(dir: &Path) -> io::Result<()> {
+    let metadata = fs::metadata(dir)?;
+    if !metadata.is_dir() {
+        let msg = format!("{} is not a directory", dir.display());
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg));
+    }
+    if metadata.permissions().mode() & 0o077 != 0 {
+        let msg = format!("{} is accessible by other users", dir.display());
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, msg));
+    }
+    Ok(())
+}
+
+// A key in Tor's tagged file format, `== <tag> ==` NUL padded to 32 bytes
+// followed by the key.
+fn tagged(tag: &str, key: &[u8]) -> Vec<u8> {
+    let mut contents = vec![0u8; TAG_LEN];
+    contents[..tag.len()].copy_from_slice(tag.as_bytes());
+    contents.extend_from_slice(key);
+    contents
+}
+
+/// Writes `contents` to a new file only readable by the current user.
+pub fn write_secret(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn onion_key_round_trips_with_restrictive_permissions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("onion.key");
+        let key = format!("ED25519-V3:{}", BASE64.encode(&[7u8; ED25519_V3_KEY_LEN]));
+
+        assert_eq!(read_onion_key(&path).unwrap(), None);
+        write_onion_key(&path, &key).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        let got = read_onion_key(&path).unwrap();
+        let overwrite = write_onion_key(&path, &key);
+
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(got, Some(OnionKey::Existing(key)));
+        assert!(overwrite.is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_onion_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("onion.key");
+        fs::write(&path, "RSA1024:abcd\n").unwrap();
+        let got = read_onion_key(&path);
+
+        assert_eq!(got.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn identity_is_generated_once_then_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("id.key");
+
+        let missing = IdentitySource::File(path.clone()).keypair();
+        let generated = IdentitySource::FileOrGenerate(path.clone())
+            .keypair()
+            .unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        let loaded = IdentitySource::File(path.clone()).keypair().unwrap();
+        let reloaded = IdentitySource::FileOrGenerate(path.clone())
+            .keypair()
+            .unwrap();
+
+        assert_eq!(
+            missing.err().map(|e| e.kind()),
+            Some(io::ErrorKind::NotFound)
+        );
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(loaded.public(), generated.public());
+        assert_eq!(reloaded.public(), generated.public());
+    }
+
+    #[test]
+    fn hidden_service_dir_matches_tor_format() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("hidden_service");
+        let keypair = ed25519::Keypair::generate();
+
+        let address = write_hidden_service_dir(&dir, &keypair, 7).unwrap();
+        let secret = fs::read(dir.join("hs_ed25519_secret_key")).unwrap();
+        let public = fs::read(dir.join("hs_ed25519_public_key")).unwrap();
+        let hostname = fs::read_to_string(dir.join("hostname")).unwrap();
+        let mode = fs::metadata(&dir).unwrap().permissions().mode();
+        let overwrite = write_hidden_service_dir(&dir, &keypair, 7);
+
+        assert_eq!(&secret[..TAG_LEN], b"== ed25519v1-secret: type0 ==\0\0\0");
+        assert_eq!(secret.len(), TAG_LEN + 64);
+        assert_eq!(&public[..TAG_LEN], b"== ed25519v1-public: type0 ==\0\0\0");
+        assert_eq!(&public[TAG_LEN..], &keypair.public().encode()[..]);
+        assert_eq!(mode & 0o777, 0o700);
+        assert!(overwrite.is_err());
+
+        // The hostname passes the checksum and names the key.
+        let parsed: OnionAddress = format!("{}:7", hostname.trim()).parse().unwrap();
+        assert_eq!(parsed, address);
+        assert_eq!(
+            parsed.to_public_key(),
+            Some(libp2p::identity::PublicKey::Ed25519(keypair.public()))
+        );
+    }
+
+    #[test]
+    fn hidden_service_dir_must_be_private() {
+        let tmp = tempfile::tempdir().unwrap();
+        let shared = tmp.path().join("shared");
+        fs::create_dir(&shared).unwrap();
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o750)).unwrap();
+        let keypair = ed25519::Keypair::generate();
+
+        let in_shared = write_hidden_service_dir(&shared, &keypair, 7);
+        let nested = write_hidden_service_dir(&tmp.path().join("a/b"), &keypair, 7);
+
+        assert_eq!(
+            in_shared.unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        assert_eq!(nested.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(!shared.join("hs_ed25519_secret_key").exists());
+    }
+}
@@ -0,0 +1,487 @@
+//! Passphrase encrypted keystore.
+//!
+//! Holds the swarm's libp2p identity and onion service keys, all ed25519
+//! secret keys, so that they are never stored in plaintext. The file is
+//! text: a header naming the KDF and cipher with their parameters,
+//! followed by the base64 encoded ciphertext.
+//!
+//! ```text
+//! ping-pong keystore v1
+//! scrypt <log_n> <r> <p> <salt>
+//! chacha20poly1305 <nonce>
+//! <ciphertext>
+//! ```
+//!
+//! The key is derived from the passphrase with scrypt, the header is
+//! authenticated as associated data.
+
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use data_encoding::BASE64;
+use libp2p::core::identity::{self, ed25519};
+use rand::RngCore;
+use zeroize::Zeroizing;
+
+use crate::{
+    control::OnionKey,
+    keys,
+    onion::{OnionAddress, PUBKEY_LEN},
+};
+
+const MAGIC: &str = "ping-pong keystore v1";
+
+/// scrypt cost, 2^15 iterations with r = 8 take 32 MiB of memory.
+const DEFAULT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+/// Largest scrypt cost accepted from a keystore header, 2^18 iterations with
+/// r = 8 take 256 MiB, so a crafted file cannot exhaust memory or CPU.
+const MAX_LOG_N: u8 = 18;
+const MAX_R: u32 = 8;
+const MAX_P: u32 = 4;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+
+/// Secret keys stored encrypted under a passphrase.
+#[derive(Clone, Default)]
+pub struct Keystore {
+    identity: Option<ed25519::Keypair>,
+    onion_keys: BTreeMap<String, ed25519::Keypair>,
+}
+
+impl Keystore {
+    /// Creates an empty keystore.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The swarm's identity, if stored.
+    pub fn identity(&self) -> Option<identity::Keypair> {
+        self.identity.clone().map(identity::Keypair::Ed25519)
+    }
+
+    /// Stores `keypair` as the swarm's identity.
+    pub fn set_identity(&mut self, keypair: ed25519::Keypair) {
+        self.identity = Some(keypair);
+    }
+
+    /// The onion service key stored as `name`, in the form `ADD_ONION`
+    /// expects.
+    pub fn onion_key(&self, name: &str) -> Option<OnionKey> {
+        let keypair = self.onion_keys.get(name)?.clone();
+        OnionKey::from_identity(&identity::Keypair::Ed25519(keypair))
+    }
+
+    /// Stores `keypair` as the onion service key `name`, a single line.
+    pub fn set_onion_key(&mut self, name: &str, keypair: ed25519::Keypair) {
+        self.onion_keys.insert(name.replace('\n', " "), keypair);
+    }
+
+    /// The name and onion address, with virtual port `port`, of each stored
+    /// onion service key.
+    pub fn onion_addresses(&self, port: u16) -> impl Iterator<Item = (&str, OnionAddress)> {
+        self.onion_keys.iter().map(move |(name, keypair)| {
+            (
+                name.as_str(),
+                OnionAddress::v3(keypair.public().encode(), port),
+            )
+        })
+    }
+
+    /// Reads and decrypts the keystore at `path`.
+    pub fn read(path: &Path, passphrase: &Passphrase) -> Result<Self, KeystoreError> {
+        decrypt(&fs::read_to_string(path)?, passphrase)
+    }
+
+    /// Encrypts the keystore and writes it to `path` (mode 0600), replacing
+    /// any existing file.
+    pub fn write(&self, path: &Path, passphrase: &Passphrase) -> Result<(), KeystoreError> {
+        let contents = encrypt(self, passphrase, DEFAULT_LOG_N)?;
+
+        // Write to a new file first so a failure never loses the keystore.
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let _ = fs::remove_file(&tmp);
+        keys::write_secret(&tmp, contents.as_bytes())?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Keystore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keystore")
+            .field("identity", &self.identity.as_ref().map(|k| k.public()))
+            .field("onion_keys", &self.onion_keys.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// A keystore passphrase, wiped from memory when dropped.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Passphrase(Zeroizing<String>);
+
+impl Passphrase {
+    /// Wraps `passphrase`.
+    pub fn new(passphrase: impl Into<String>) -> Self {
+        Passphrase(Zeroizing::new(passphrase.into()))
+    }
+}
+
+impl fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Passphrase(..)")
+    }
+}
+
+/// Where the passphrase is read from.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum PassphraseSource {
+    /// Prompt for it on the terminal.
+    #[default]
+    Prompt,
+    /// The named environment variable.
+    Env(String),
+    /// The first line read from an open file descriptor.
+    Fd(u32),
+}
+
+impl PassphraseSource {
+    /// Reads the passphrase, showing `prompt` if prompting.
+    pub fn read(&self, prompt: &str) -> io::Result<Passphrase> {
+        let passphrase = Zeroizing::new(match self {
+            PassphraseSource::Prompt => rpassword::read_password_from_tty(Some(prompt))?,
+            PassphraseSource::Env(var) => std::env::var(var).map_err(|_| {
+                let msg = format!("passphrase variable {} not set", var);
+                io::Error::new(io::ErrorKind::NotFound, msg)
+            })?,
+            PassphraseSource::Fd(fd) => read_line(fs::File::open(format!("/dev/fd/{}", fd))?)?,
+        });
+        if passphrase.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "empty passphrase",
+            ));
+        }
+        Ok(Passphrase(passphrase))
+    }
+
+    /// Reads a new passphrase, prompting twice to catch typos.
+    pub fn read_new(&self, prompt: &str) -> io::Result<Passphrase> {
+        let passphrase = self.read(prompt)?;
+        if *self == PassphraseSource::Prompt && self.read("Repeat passphrase: ")? != passphrase {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "passphrases do not match",
+            ));
+        }
+        Ok(passphrase)
+    }
+}
+
+// Reads up to the first newline only, as the writer may keep the fd open,
+// byte by byte so nothing after it is consumed.
+fn read_line(mut reader: impl Read) -> io::Result<String> {
+    let mut line = Zeroizing::new(Vec::new());
+    let mut byte = [0u8; 1];
+    while reader.read(&mut byte)? == 1 && byte[0] != b'\n' {
+        line.push(byte[0]);
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line.to_vec())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "passphrase is not UTF-8"))
+}
+
+/// Errors reading or writing a keystore.
+#[derive(Debug)]
+pub enum KeystoreError {
+    /// I/O error accessing the keystore file.
+    Io(io::Error),
+    /// The file is not a keystore we understand.
+    Format(String),
+    /// Wrong passphrase, or the keystore was modified.
+    Decrypt,
+}
+
+impl fmt::Display for KeystoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeystoreError::Io(e) => write!(f, "keystore I/O error: {}", e),
+            KeystoreError::Format(msg) => write!(f, "invalid keystore: {}", msg),
+            KeystoreError::Decrypt => {
+                write!(
+                    f,
+                    "failed to decrypt keystore, wrong passphrase or corrupted file"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for KeystoreError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KeystoreError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for KeystoreError {
+    fn from(e: io::Error) -> Self {
+        KeystoreError::Io(e)
+    }
+}
+
+impl From<KeystoreError> for io::Error {
+    fn from(e: KeystoreError) -> Self {
+        match e {
+            KeystoreError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+fn encrypt(
+    keystore: &Keystore,
+    passphrase: &Passphrase,
+    log_n: u8,
+) -> Result<String, KeystoreError> {
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let header = format!(
+        "{}\nscrypt {} {} {} {}\nchacha20poly1305 {}\n",
+        MAGIC,
+        log_n,
+        SCRYPT_R,
+        SCRYPT_P,
+        BASE64.encode(&salt),
+        BASE64.encode(&nonce)
+    );
+    let cipher = cipher(passphrase, log_n, SCRYPT_R, SCRYPT_P, &salt)?;
+    let plaintext = plaintext(keystore);
+    let payload = Payload {
+        msg: plaintext.as_bytes(),
+        aad: header.as_bytes(),
+    };
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), payload)
+        .map_err(|_| KeystoreError::Format("encryption failed".to_owned()))?;
+
+    Ok(format!("{}{}\n", header, BASE64.encode(&ciphertext)))
+}
+
+fn decrypt(contents: &str, passphrase: &Passphrase) -> Result<Keystore, KeystoreError> {
+    let format = |msg: &str| KeystoreError::Format(msg.to_owned());
+    let mut lines = contents.lines();
+
+    if lines.next() != Some(MAGIC) {
+        return Err(format("unknown format"));
+    }
+    let kdf = lines.next().ok_or_else(|| format("missing KDF"))?;
+    let aead = lines.next().ok_or_else(|| format("missing cipher"))?;
+    let ciphertext = lines.next().ok_or_else(|| format("missing ciphertext"))?;
+
+    let (log_n, r, p, salt) = match kdf.split(' ').collect::<Vec<_>>()[..] {
+        ["scrypt", log_n, r, p, salt] => (
+            log_n.parse().map_err(|_| format("invalid scrypt log_n"))?,
+            r.parse().map_err(|_| format("invalid scrypt r"))?,
+            p.parse().map_err(|_| format("invalid scrypt p"))?,
+            decode(salt)?,
+        ),
+        _ => return Err(format("unsupported KDF")),
+    };
+    if log_n > MAX_LOG_N || r > MAX_R || p > MAX_P {
+        return Err(format("scrypt parameters too expensive"));
+    }
+    let nonce = match aead.split(' ').collect::<Vec<_>>()[..] {
+        ["chacha20poly1305", nonce] => decode(nonce)?,
+        _ => return Err(format("unsupported cipher")),
+    };
+    if nonce.len() != NONCE_LEN {
+        return Err(format("invalid nonce"));
+    }
+
+    let header = format!("{}\n{}\n{}\n", MAGIC, kdf, aead);
+    let cipher = cipher(passphrase, log_n, r, p, &salt)?;
+    let payload = Payload {
+        msg: &decode(ciphertext)?,
+        aad: header.as_bytes(),
+    };
+    let plaintext = Zeroizing::new(
+        cipher
+            .decrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| KeystoreError::Decrypt)?,
+    );
+    let plaintext = std::str::from_utf8(&plaintext).map_err(|_| KeystoreError::Decrypt)?;
+
+    parse_plaintext(plaintext)
+}
+
+fn cipher(
+    passphrase: &Passphrase,
+    log_n: u8,
+    r: u32,
+    p: u32,
+    salt: &[u8],
+) -> Result<ChaCha20Poly1305, KeystoreError> {
+    let params = scrypt::Params::new(log_n, r, p, KEY_LEN)
+        .map_err(|_| KeystoreError::Format("invalid scrypt parameters".to_owned()))?;
+    let mut key = Zeroizing::new([0u8; KEY_LEN]);
+    scrypt::scrypt(passphrase.0.as_bytes(), salt, &params, &mut *key)
+        .map_err(|_| KeystoreError::Format("invalid scrypt parameters".to_owned()))?;
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&*key)))
+}
+
+// One key per line, `identity <secret>` or `onion <secret> <name>`.
+fn plaintext(keystore: &Keystore) -> Zeroizing<String> {
+    let secret = |keypair: &ed25519::Keypair| BASE64.encode(keypair.secret().as_ref());
+    let mut plaintext = Zeroizing::new(String::new());
+    if let Some(keypair) = &keystore.identity {
+        plaintext.push_str(&format!("identity {}\n", secret(keypair)));
+    }
+    for (name, keypair) in &keystore.onion_keys {
+        plaintext.push_str(&format!("onion {} {}\n", secret(keypair), name));
+    }
+    plaintext
+}
+
+fn parse_plaintext(plaintext: &str) -> Result<Keystore, KeystoreError> {
+    let mut keystore = Keystore::new();
+    for line in plaintext.lines() {
+        match line.splitn(3, ' ').collect::<Vec<_>>()[..] {
+            ["identity", secret] => keystore.identity = Some(decode_secret(secret)?),
+            ["onion", secret, name] => {
+                keystore.set_onion_key(name, decode_secret(secret)?);
+            }
+            _ => return Err(KeystoreError::Format("invalid key entry".to_owned())),
+        }
+    }
+    Ok(keystore)
+}
+
+fn decode(s: &str) -> Result<Vec<u8>, KeystoreError> {
+    BASE64
+        .decode(s.as_bytes())
+        .map_err(|_| KeystoreError::Format("invalid base64".to_owned()))
+}
+
+fn decode_secret(s: &str) -> Result<ed25519::Keypair, KeystoreError> {
+    let bytes = decode(s)?;
+    if bytes.len() != PUBKEY_LEN {
+        return Err(KeystoreError::Format(
+            "invalid ed25519 secret key".to_owned(),
+        ));
+    }
+    let secret = ed25519::SecretKey::from_bytes(bytes)
+        .map_err(|_| KeystoreError::Format("invalid ed25519 secret key".to_owned()))?;
+    Ok(ed25519::Keypair::from(secret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Cheap KDF parameters, the default takes seconds in debug builds.
+    const TEST_LOG_N: u8 = 4;
+
+    fn keystore() -> Keystore {
+        let mut keystore = Keystore::new();
+        keystore.set_identity(ed25519::Keypair::generate());
+        keystore.set_onion_key("listener", ed25519::Keypair::generate());
+        keystore
+    }
+
+    #[test]
+    fn round_trips_with_passphrase() {
+        let keystore = keystore();
+        let passphrase = Passphrase::new("correct horse battery staple");
+
+        let encrypted = encrypt(&keystore, &passphrase, TEST_LOG_N).unwrap();
+        let decrypted = decrypt(&encrypted, &passphrase).unwrap();
+
+        assert!(encrypted.starts_with("ping-pong keystore v1\nscrypt 4 8 1 "));
+        assert_eq!(
+            decrypted.identity().map(|k| k.public()),
+            keystore.identity().map(|k| k.public())
+        );
+        assert_eq!(
+            decrypted.onion_key("listener"),
+            keystore.onion_key("listener")
+        );
+        assert_eq!(decrypted.onion_addresses(7).count(), 1);
+    }
+
+    #[test]
+    fn rejects_wrong_passphrase() {
+        let encrypted = encrypt(&keystore(), &Passphrase::new("right"), TEST_LOG_N).unwrap();
+
+        match decrypt(&encrypted, &Passphrase::new("wrong")) {
+            Err(KeystoreError::Decrypt) => {}
+            other => panic!("expected Decrypt, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn header_is_authenticated() {
+        let passphrase = Passphrase::new("passphrase");
+        let encrypted = encrypt(&keystore(), &passphrase, TEST_LOG_N).unwrap();
+        // Same KDF output, but a header that was not encrypted.
+        let tampered = encrypted.replacen("scrypt 4 8 1", "scrypt 04 8 1", 1);
+
+        assert!(matches!(
+            decrypt(&tampered, &passphrase),
+            Err(KeystoreError::Decrypt)
+        ));
+    }
+
+    #[test]
+    fn rejects_expensive_scrypt_parameters() {
+        let passphrase = Passphrase::new("passphrase");
+        let encrypted = encrypt(&keystore(), &passphrase, TEST_LOG_N).unwrap();
+
+        for kdf in &["scrypt 40 8 1", "scrypt 4 1024 1", "scrypt 4 8 64"] {
+            let crafted = encrypted.replacen("scrypt 4 8 1", kdf, 1);
+            match decrypt(&crafted, &passphrase) {
+                Err(KeystoreError::Format(_)) => {}
+                other => panic!("expected Format for {}, got {:?}", kdf, other),
+            }
+        }
+    }
+
+    #[test]
+    fn reads_passphrase_from_env() {
+        std::env::set_var("PING_PONG_TEST_PASSPHRASE", "from env");
+        let source = PassphraseSource::Env("PING_PONG_TEST_PASSPHRASE".to_owned());
+
+        assert_eq!(source.read("").unwrap(), Passphrase::new("from env"));
+        assert!(PassphraseSource::Env("PING_PONG_UNSET".to_owned())
+            .read("")
+            .is_err());
+    }
+
+    #[test]
+    fn reads_first_line_without_waiting_for_eof() {
+        let (reader, mut writer) = std::os::unix::net::UnixStream::pair().unwrap();
+        io::Write::write_all(&mut writer, b"first line\r\nsecond line\n").unwrap();
+
+        // `writer` is still open, reading to the end would block.
+        assert_eq!(read_line(reader).unwrap(), "first line");
+    }
+}
@@ -0,0 +1,436 @@
+//! Launching a private Tor process.
+//!
+//! Instead of relying on a system Tor, configured by hand and run as root,
+//! we can start our own `tor` with a generated torrc, a temporary data
+//! directory and SOCKS and control ports picked by Tor itself.
+
+use std::{
+    env, fmt,
+    fs::{self, DirBuilder},
+    io,
+    net::SocketAddr,
+    os::unix::fs::{DirBuilderExt, PermissionsExt},
+    path::{Path, PathBuf},
+    process::{self, Stdio},
+    time::Duration,
+};
+
+use futures::future::{self, Either};
+use futures_timer::Delay;
+use log::{debug, info};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    process::{Child, ChildStdout, Command},
+};
+
+use crate::{
+    control::{ControlConfig, ControlError},
+    endpoint::LocalEndpoint,
+};
+
+/// How long we wait for a new Tor to bootstrap by default.
+pub const DEFAULT_BOOTSTRAP_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// File in the data directory Tor writes its control port to.
+const CONTROL_PORT_FILE: &str = "control_port";
+
+/// Number of Tor log lines kept to explain a failed launch.
+const LOG_TAIL: usize = 5;
+
+/// Attempts at picking an unused data directory name.
+const DATA_DIR_ATTEMPTS: usize = 16;
+
+/// Configuration for launching Tor.
+#[derive(Debug, Clone)]
+pub struct TorLauncher {
+    binary: PathBuf,
+    bootstrap_timeout: Duration,
+}
+
+impl Default for TorLauncher {
+    fn default() -> Self {
+        TorLauncher {
+            binary: PathBuf::from("tor"),
+            bootstrap_timeout: DEFAULT_BOOTSTRAP_TIMEOUT,
+        }
+    }
+}
+
+impl TorLauncher {
+    /// Launches the `tor` found in `PATH`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the Tor binary to run.
+    pub fn binary(mut self, binary: impl Into<PathBuf>) -> Self {
+        self.binary = binary.into();
+        self
+    }
+
+    /// Sets how long to wait for Tor to bootstrap.
+    pub fn bootstrap_timeout(mut self, timeout: Duration) -> Self {
+        self.bootstrap_timeout = timeout;
+        self
+    }
+
+    /// Starts Tor and waits until it has bootstrapped.
+    pub async fn launch(&self) -> Result<TorProcess, LaunchError> {
+        let dir = DataDir::create()?;
+
+        let torrc = dir.0.join("torrc");
+        fs::write(&torrc, torrc_contents(&dir.0))?;
+
+        info!("starting {} in {}", self.binary.display(), dir.0.display());
+        let mut child = Command::new(&self.binary)
+            .arg("-f")
+            .arg(&torrc)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => LaunchError::NotFound(self.binary.clone()),
+                _ => LaunchError::Io(e),
+            })?;
+
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let mut lines = BufReader::new(stdout).lines();
+        let bootstrap = Box::pin(async {
+            let mut tail = Vec::new();
+            while let Some(line) = lines.next_line().await? {
+                debug!("tor: {}", line);
+                if line.contains("Bootstrapped 100%") {
+                    return Ok(());
+                }
+                if tail.len() == LOG_TAIL {
+                    tail.remove(0);
+                }
+                tail.push(line);
+            }
+            Err(LaunchError::Exited(tail))
+        });
+        match future::select(bootstrap, Delay::new(self.bootstrap_timeout)).await {
+            Either::Left((res, _)) => res?,
+            Either::Right(_) => return Err(LaunchError::BootstrapTimeout(self.bootstrap_timeout)),
+        }
+
+        // Keep draining Tor's log so it never blocks on a full pipe.
+        tokio::spawn(log_lines(lines));
+
+        let control_port = read_control_port(&dir.0.join(CONTROL_PORT_FILE))?;
+        let mut tor = TorProcess {
+            _child: child,
+            socks_port: 0,
+            control_port,
+            dir,
+        };
+        let mut control = tor.control_config().connect().await?;
+        let listeners = control.get_info("net/listeners/socks").await?;
+        tor.socks_port = parse_listeners(&listeners)
+            .ok_or(ControlError::Protocol("no SOCKS listener"))?
+            .port();
+        info!(
+            "Tor bootstrapped, SOCKS port {}, control port {}",
+            tor.socks_port, control_port
+        );
+
+        Ok(tor)
+    }
+}
+
+/// A running Tor, killed when this is dropped.
+#[derive(Debug)]
+pub struct TorProcess {
+    _child: Child,
+    socks_port: u16,
+    control_port: u16,
+    dir: DataDir,
+}
+
+impl TorProcess {
+    /// The local SOCKS port.
+    pub fn socks_port(&self) -> u16 {
+        self.socks_port
+    }
+
+    /// The SOCKS proxy endpoint.
+    pub fn socks_endpoint(&self) -> LocalEndpoint {
+        LocalEndpoint::localhost(self.socks_port)
+    }
+
+    /// How to connect to the control port.
+    pub fn control_config(&self) -> ControlConfig {
+        ControlConfig::new(LocalEndpoint::localhost(self.control_port))
+            .cookie_file(self.dir.0.join("control_auth_cookie"))
+    }
+}
+
+/// Removes the data directory on drop.
+#[derive(Debug)]
+struct DataDir(PathBuf);
+
+impl DataDir {
+    /// Creates a new directory only we can access, in `$XDG_RUNTIME_DIR` if
+    /// set. Tor keeps its control port cookie there, so we never reuse a
+    /// directory someone else created.
+    fn create() -> io::Result<Self> {
+        let base = env::var_os("XDG_RUNTIME_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(env::temp_dir);
+
+        for _ in 0..DATA_DIR_ATTEMPTS {
+            let path = base.join(format!("ping-pong-tor-{:016x}", rand::random::<u64>()));
+            match DirBuilder::new().mode(0o700).create(&path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+            let dir = DataDir(path);
+            // The umask may have cleared bits Tor requires.
+            fs::set_permissions(&dir.0, fs::Permissions::from_mode(0o700))?;
+            return Ok(dir);
+        }
+
+        let msg = format!("no unused data directory name in {}", base.display());
+        Err(io::Error::new(io::ErrorKind::AlreadyExists, msg))
+    }
+}
+
+impl Drop for DataDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.0) {
+            debug!("failed to remove {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+/// Tor picks free ports itself, which unlike picking them for it cannot race
+/// with other processes binding them first.
+fn torrc_contents(data_dir: &Path) -> String {
+    format!(
+        "DataDirectory {dir}\n\
+         SocksPort auto ExtendedErrors\n\
+         ControlPort auto\n\
+         ControlPortWriteToFile {dir}/{control_port_file}\n\
+         CookieAuthentication 1\n\
+         AvoidDiskWrites 1\n\
+         Log notice stdout\n\
+         __OwningControllerProcess {pid}\n",
+        dir = data_dir.display(),
+        control_port_file = CONTROL_PORT_FILE,
+        pid = process::id(),
+    )
+}
+
+/// Reads the control port from the `PORT=127.0.0.1:9051` line Tor writes
+/// to its `ControlPortWriteToFile`.
+fn read_control_port(path: &Path) -> io::Result<u16> {
+    let contents = fs::read_to_string(path)?;
+    contents
+        .lines()
+        .find_map(|line| line.strip_prefix("PORT="))
+        .and_then(|addr| addr.trim().parse::<SocketAddr>().ok())
+        .map(|addr| addr.port())
+        .ok_or_else(|| {
+            let msg = format!("{}: no control port", path.display());
+            io::Error::new(io::ErrorKind::InvalidData, msg)
+        })
+}
+
+/// The first address of a `GETINFO net/listeners/*` value, a list of quoted
+/// addresses.
+fn parse_listeners(value: &str) -> Option<SocketAddr> {
+    value
+        .split_whitespace()
+        .find_map(|addr| addr.trim_matches('"').parse().ok())
+}
+
+async fn log_lines(mut lines: tokio::io::Lines<BufReader<ChildStdout>>) {
+    while let Ok(Some(line)) = lines.next_line().await {
+        debug!("tor: {}", line);
+    }
+}
+
+/// Errors launching Tor.
+#[derive(Debug)]
+pub enum LaunchError {
+    /// The Tor binary does not exist.
+    NotFound(PathBuf),
+    /// I/O error setting up or running Tor.
+    Io(io::Error),
+    /// Querying the new Tor over its control port failed.
+    Control(ControlError),
+    /// Tor exited before it bootstrapped, with its last log lines.
+    Exited(Vec<String>),
+    /// Tor did not bootstrap in time.
+    BootstrapTimeout(Duration),
+}
+
+impl fmt::Display for LaunchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LaunchError::NotFound(binary) => write!(
+                f,
+                "Tor binary not found: {}, is Tor installed?",
+                binary.display()
+            ),
+            LaunchError::Io(e) => write!(f, "failed to run Tor: {}", e),
+            LaunchError::Control(e) => write!(f, "failed to query Tor's ports: {}", e),
+            LaunchError::Exited(tail) => {
+                write!(f, "Tor exited before bootstrapping")?;
+                if let Some(last) = tail.last() {
+                    write!(f, ": {}", last)?;
+                }
+                Ok(())
+            }
+            LaunchError::BootstrapTimeout(timeout) => {
+                write!(f, "Tor did not bootstrap within {:?}", timeout)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LaunchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LaunchError::Io(e) => Some(e),
+            LaunchError::Control(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for LaunchError {
+    fn from(e: io::Error) -> Self {
+        LaunchError::Io(e)
+    }
+}
+
+impl From<ControlError> for LaunchError {
+    fn from(e: ControlError) -> Self {
+        LaunchError::Control(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use tokio::io::AsyncWriteExt;
+
+    /// Writes an executable shell script standing in for tor to `dir`.
+    fn fake_tor(dir: &Path, script: &str) -> PathBuf {
+        let path = dir.join("tor");
+        fs::write(&path, format!("#!/bin/sh\n{}", script)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    /// Runs a fake control port answering the launcher's queries, Tor's
+    /// SOCKS port being 9150.
+    async fn fake_control_port() -> u16 {
+        let mut listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let (sock, _) = listener.accept().await.unwrap();
+            let mut sock = BufReader::new(sock);
+            for reply in &[
+                "250-PROTOCOLINFO 1\r\n250-AUTH METHODS=NULL\r\n250 OK\r\n",
+                "250 OK\r\n",
+                "250-net/listeners/socks=\"127.0.0.1:9150\"\r\n250 OK\r\n",
+            ] {
+                let mut line = String::new();
+                sock.read_line(&mut line).await.unwrap();
+                sock.get_mut().write_all(reply.as_bytes()).await.unwrap();
+            }
+        });
+
+        port
+    }
+
+    #[test]
+    fn torrc_lets_tor_pick_ports() {
+        let torrc = torrc_contents(Path::new("/tmp/tor"));
+
+        assert!(torrc.contains("DataDirectory /tmp/tor\n"));
+        assert!(torrc.contains("SocksPort auto ExtendedErrors\n"));
+        assert!(torrc.contains("ControlPort auto\n"));
+        assert!(torrc.contains("ControlPortWriteToFile /tmp/tor/control_port\n"));
+    }
+
+    #[test]
+    fn parses_ports_reported_by_tor() {
+        let listeners = r#""127.0.0.1:9150" "[::1]:9150""#;
+
+        assert_eq!(
+            parse_listeners(listeners),
+            Some("127.0.0.1:9150".parse().unwrap())
+        );
+        assert_eq!(parse_listeners(""), None);
+    }
+
+    #[test]
+    fn data_dir_is_new_and_private() {
+        let first = DataDir::create().unwrap();
+        let second = DataDir::create().unwrap();
+        let mode = fs::metadata(&first.0).unwrap().permissions().mode();
+
+        assert_ne!(first.0, second.0);
+        assert_eq!(mode & 0o777, 0o700);
+    }
+
+    #[tokio::test]
+    async fn reports_missing_binary() {
+        let launcher = TorLauncher::new().binary("/nonexistent/tor");
+
+        match launcher.launch().await {
+            Err(LaunchError::NotFound(binary)) => assert_eq!(binary, Path::new("/nonexistent/tor")),
+            other => panic!("expected NotFound, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn waits_for_bootstrap() {
+        let control_port = fake_control_port().await;
+        let dir = tempfile::tempdir().unwrap();
+        let binary = fake_tor(
+            dir.path(),
+            &format!(
+                "f=$(sed -n 's/^ControlPortWriteToFile //p' \"$2\")\n\
+                 echo PORT=127.0.0.1:{} > \"$f\"\n\
+                 echo '[notice] Bootstrapped 50% (loading_descriptors)'\n\
+                 echo '[notice] Bootstrapped 100% (done): Done'\n\
+                 sleep 5\n",
+                control_port
+            ),
+        );
+
+        let tor = TorLauncher::new()
+            .binary(&binary)
+            .launch()
+            .await
+            .expect("launch failed");
+
+        assert_eq!(tor.socks_port(), 9150);
+        assert_eq!(tor.socks_endpoint(), LocalEndpoint::localhost(9150));
+        assert_eq!(
+            tor.control_config().endpoint(),
+            &LocalEndpoint::localhost(control_port)
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_early_exit() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = fake_tor(dir.path(), "echo '[err] Reading config failed'\nexit 1\n");
+
+        match TorLauncher::new().binary(&binary).launch().await {
+            Err(LaunchError::Exited(tail)) => assert_eq!(tail, vec!["[err] Reading config failed"]),
+            other => panic!("expected Exited, got {:?}", other),
+        }
+    }
+}
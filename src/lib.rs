@@ -1,47 +1,76 @@
 mod cli;
+pub mod client_auth;
+pub mod control;
+pub mod endpoint;
+pub mod keys;
+pub mod keystore;
+pub mod launch;
+mod limits;
+pub mod onion;
+pub mod peer;
+pub mod proxy_protocol;
+pub mod psk;
+pub mod security;
+pub mod service;
+pub mod socks;
+#[cfg(test)]
+mod testing;
+pub mod torrc;
 pub mod transport;
 
-pub use cli::Opt;
+pub use cli::{passphrase_source, Command, Opt};
 
 use std::{
+    convert::TryFrom,
     io,
     net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::Path,
     pin::Pin,
+    sync::Arc,
     task::{Context, Poll},
     time::Duration,
     collections::HashMap,
 };
 
-use anyhow::{Result};
+use anyhow::{Context as _, Result};
 use futures::{future, prelude::*};
 use libp2p::{
     core::{
         either::EitherError,
         muxing::StreamMuxerBox,
-        transport::{boxed::Boxed, timeout::TransportTimeoutError},
+        transport::{boxed::Boxed, timeout::TransportTimeoutError, upgrade::Builder},
         upgrade::{SelectUpgrade, Version},
-        UpgradeError,
+        ConnectionInfo, UpgradeError,
     },
-    dns::{DnsConfig, DnsErr},
     identity,
     mplex::MplexConfig,
     ping::{Ping, PingConfig},
-    secio::{SecioConfig, SecioError},
     swarm::SwarmBuilder,
     yamux, Multiaddr, PeerId, Swarm, Transport,
 };
-use tokio::net::TcpStream;
-use tokio_socks::{tcp::Socks5Stream, IntoTargetAddr};
 
-use crate::transport::TorTokioTcpConfig;
+use crate::{
+    client_auth::ClientAuthKey,
+    control::ControlConfig,
+    endpoint::{LocalEndpoint, LocalStream},
+    onion::OnionAddress,
+    peer::PeerError,
+    security::HandshakeError,
+    service::EphemeralOnion,
+    socks::{Credentials, SocksError, TargetAddr},
+    transport::TorTokioTcpConfig,
+};
 
 /// Entry point to run the ping-pong application as a dialer.
-pub async fn run_dialer(addr: Multiaddr) -> Result<()> {
-    let map = HashMap::new();
+pub async fn run_dialer(
+    addr: Multiaddr,
+    id_keys: identity::Keypair,
+    tor: TorTokioTcpConfig,
+) -> Result<()> {
     let config = PingConfig::new()
         .with_keep_alive(true)
         .with_interval(Duration::from_secs(1));
-    let mut swarm = crate::build_swarm(config, map)?;
+    let mut swarm = crate::build_swarm(config, id_keys, tor)?;
 
     Swarm::dial_addr(&mut swarm, addr).unwrap();
 
@@ -57,34 +86,110 @@ pub async fn run_dialer(addr: Multiaddr) -> Result<()> {
     Ok(())
 }
 
+/// Waits until the Tor behind `control` is fully bootstrapped, giving up
+/// after `timeout`. Dials made before then just fail, or run into the
+/// transport's timeout.
+pub async fn wait_for_bootstrap(control: &ControlConfig, timeout: Duration) -> Result<()> {
+    let mut control = control.connect().await?;
+    control.wait_for_bootstrap(timeout).await?;
+    Ok(())
+}
+
 /// Entry point to run the ping-pong application as a listener.
-pub async fn run_listener(onion: Multiaddr) -> Result<()> {
-    let map = onion_port_map(onion.clone());
+///
+/// Listens on the onion services configured in the torrc at `torrc`,
+/// announcing `onion` or, if `None`, the first service's first port.
+pub async fn run_listener(
+    torrc: &Path,
+    onion: Option<Multiaddr>,
+    id_keys: identity::Keypair,
+    tor: TorTokioTcpConfig,
+) -> Result<()> {
+    let services =
+        torrc::read(torrc).with_context(|| format!("failed to read torrc: {}", torrc.display()))?;
+    let map = torrc::onion_port_map(&services)
+        .with_context(|| format!("failed to read onion services from {}", torrc.display()))?;
+
+    let onion = match onion {
+        Some(onion) => onion,
+        None => {
+            let (service, port) = services
+                .iter()
+                .find_map(|s| s.ports.first().map(|p| (s, p.virtual_port)))
+                .with_context(|| format!("no onion service in {}", torrc.display()))?;
+            let host = service.hostname()?.host();
+            let addr: OnionAddress = format!("{}:{}", host, port).parse()?;
+            Multiaddr::empty().with(addr.to_protocol())
+        }
+    };
+    if !map.contains_key(&onion) {
+        anyhow::bail!("{} is not configured in {}", onion, torrc.display());
+    }
+
+    listen(onion, id_keys, tor.onion_map(map)).await
+}
+
+/// Entry point to run the ping-pong application as a listener on an
+/// ephemeral onion service, created via the Tor control port.
+///
+/// Connections to the service are forwarded to a free local port. We only
+/// start listening once the service's descriptor is published. The onion
+/// service is removed again on Ctrl-C.
+pub async fn run_ephemeral_listener(
+    onion: EphemeralOnion,
+    id_keys: identity::Keypair,
+    tor: TorTokioTcpConfig,
+) -> Result<()> {
+    let local = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, free_local_port()?));
+    let service = onion.create(local, &id_keys).await?;
+    println!("Onion service published: {}", service.address);
+
+    let mut map = HashMap::new();
+    map.insert(service.address.clone(), local.port());
+    let res = listen(service.address.clone(), id_keys, tor.onion_map(map)).await;
+
+    service.remove().await?;
+    res
+}
+
+// Runs the listener swarm until Ctrl-C.
+async fn listen(
+    onion: Multiaddr,
+    id_keys: identity::Keypair,
+    tor: TorTokioTcpConfig,
+) -> Result<()> {
     println!("Onion service: {}", onion);
+    println!("Peer ID: {}", PeerId::from(id_keys.public()));
+    if let Some(local) = tor.local_addr(&onion) {
+        println!("Local socket: {}", local);
+    }
 
     let config = PingConfig::new().with_keep_alive(true);
-    let mut swarm = crate::build_swarm(config, map)?;
+    let mut swarm = crate::build_swarm(config, id_keys, tor)?;
 
     Swarm::listen_on(&mut swarm, onion.clone())?;
 
-    future::poll_fn(move |cx: &mut Context| loop {
+    let run = future::poll_fn(move |cx: &mut Context| loop {
         match swarm.poll_next_unpin(cx) {
             Poll::Ready(Some(event)) => println!("{:?}", event),
             Poll::Ready(None) => return Poll::Ready(()),
             Poll::Pending => return Poll::Pending,
         }
-    })
-    .await;
+    });
+    future::select(run, Box::pin(tokio::signal::ctrl_c())).await;
 
     Ok(())
 }
 
-/// Build a libp2p swarm (also called a switch).
-pub fn build_swarm(config: PingConfig, map: HashMap<Multiaddr, u16>) -> Result<Swarm<Ping>> {
-    let id_keys = identity::Keypair::generate_ed25519();
+/// Build a libp2p swarm (also called a switch) with the identity `id_keys`.
+pub fn build_swarm(
+    config: PingConfig,
+    id_keys: identity::Keypair,
+    tor: TorTokioTcpConfig,
+) -> Result<Swarm<Ping, TorConnInfo>> {
     let peer_id = PeerId::from(id_keys.public());
 
-    let transport = crate::build_transport(id_keys, map)?;
+    let transport = crate::build_transport(id_keys, tor)?;
     let behaviour = Ping::new(config);
 
     let swarm = SwarmBuilder::new(transport, behaviour, peer_id)
@@ -94,11 +199,10 @@ pub fn build_swarm(config: PingConfig, map: HashMap<Multiaddr, u16>) -> Result<S
     Ok(swarm)
 }
 
-fn onion_port_map(onion: Multiaddr) -> HashMap<Multiaddr, u16> {
-    let mut map = HashMap::new();
-    // FIMXE: This shouldn't be hard coded.
-    map.insert(onion, 7777);
-    map
+/// A local TCP port that is currently free.
+pub(crate) fn free_local_port() -> io::Result<u16> {
+    let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    Ok(listener.local_addr()?.port())
 }
 
 struct TokioExecutor;
@@ -111,41 +215,251 @@ impl libp2p::core::Executor for TokioExecutor {
 
 /// Builds a libp2p transport with the following features:
 /// - TCp connectivity
-/// - DNS name resolution
-/// - Authentication via secio
+/// - DNS name resolution (by Tor, never by the system resolver)
+/// - Authentication via secio, Noise or either, see `Security`
+/// - Verification of the remote `PeerId` against a dialed `/p2p` address,
+///   and optionally against the dialed onion service's key
 /// - Multiplexing via yamux or mplex
-pub fn build_transport(keypair: identity::Keypair, map: HashMap<Multiaddr, u16>) -> anyhow::Result<PingPongTransport> {
-    let transport = TorTokioTcpConfig::new().nodelay(true).onion_map(map);
-    let transport = DnsConfig::new(transport)?;
+///
+/// Each connection is described by a [`TorConnInfo`], which carries the Tor
+/// circuit ID of inbound connections when the listener reads PROXY headers.
+pub fn build_transport(
+    keypair: identity::Keypair,
+    tor: TorTokioTcpConfig,
+) -> anyhow::Result<PingPongTransport> {
+    let security = tor
+        .security_protocols()
+        .upgrade(keypair)
+        .context("failed to create Noise keys")?;
+    let onion_identity = tor.verifies_onion_identity();
+    let psk = tor.swarm_key();
+    let policy = Arc::new(tor.peer_access().clone());
+    let transport = tor
+        .nodelay(true)
+        .and_then(move |conn, endpoint| {
+            let circuit_id = conn.circuit_id();
+            security::secure(conn, endpoint, psk, security).map_ok(move |(peer_id, conn)| {
+                (
+                    TorConnInfo {
+                        peer_id,
+                        circuit_id,
+                    },
+                    conn,
+                )
+            })
+        })
+        .and_then(move |(info, conn), endpoint| {
+            let verified = match policy.verify(&endpoint, info.peer_id()) {
+                Ok(()) => match peer::verify(&endpoint, info.peer_id()) {
+                    Ok(()) if onion_identity => {
+                        peer::verify_onion_identity(&endpoint, info.peer_id())
+                    }
+                    res => res,
+                },
+                res => res,
+            };
+            future::ready(verified.map(|()| (info, conn)))
+        });
 
-    let transport = transport
-        .upgrade(Version::V1)
-        .authenticate(SecioConfig::new(keypair))
+    let transport = Builder::new(transport, Version::V1)
         .multiplex(SelectUpgrade::new(
             yamux::Config::default(),
             MplexConfig::new(),
         ))
-        .map(|(peer, muxer), _| (peer, StreamMuxerBox::new(muxer)))
+        .map(|(info, muxer), _| (info, StreamMuxerBox::new(muxer)))
         .timeout(Duration::from_secs(20))
         .boxed();
 
     Ok(transport)
 }
 
+/// Registers our client authorization `key` with the Tor behind `control`
+/// for the onion service `onion`, which must happen before dialing a
+/// service that only lets authorized clients connect.
+pub async fn add_client_auth(
+    control: &ControlConfig,
+    onion: &Multiaddr,
+    key: &ClientAuthKey,
+) -> Result<()> {
+    let addr = onion
+        .iter()
+        .find_map(|proto| OnionAddress::try_from(&proto).ok())
+        .with_context(|| format!("not an onion address: {}", onion))?;
+    let service_id = addr.host();
+    let service_id = service_id.trim_end_matches(".onion");
+
+    let mut control = control.connect().await?;
+    control.onion_client_auth_add(service_id, key).await?;
+    Ok(())
+}
+
 /// libp2p `Transport` for the ping-pong application.
-pub type PingPongTransport = Boxed<
-    (PeerId, StreamMuxerBox),
-    TransportTimeoutError<
-        EitherError<
-            EitherError<DnsErr<io::Error>, UpgradeError<SecioError>>,
-            UpgradeError<EitherError<io::Error, io::Error>>,
-        >,
+pub type PingPongTransport = Boxed<(TorConnInfo, StreamMuxerBox), PingPongError>;
+
+/// What we know about the remote end of a connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TorConnInfo {
+    peer_id: PeerId,
+    circuit_id: Option<u32>,
+}
+
+impl TorConnInfo {
+    /// The Tor circuit an inbound connection arrived on, if the listener reads
+    /// PROXY headers, see `TorTokioTcpConfig::proxy_protocol`.
+    pub fn circuit_id(&self) -> Option<u32> {
+        self.circuit_id
+    }
+}
+
+impl ConnectionInfo for TorConnInfo {
+    type PeerId = PeerId;
+
+    fn peer_id(&self) -> &PeerId {
+        &self.peer_id
+    }
+}
+
+/// Error type of the `PingPongTransport`.
+///
+/// Dial failures caused by Tor can be inspected with [`socks_error`].
+pub type PingPongError = TransportTimeoutError<
+    EitherError<
+        EitherError<EitherError<io::Error, HandshakeError>, PeerError>,
+        UpgradeError<EitherError<io::Error, io::Error>>,
     >,
 >;
 
+/// Finds the Tor SOCKS error that caused a dial to fail, if any.
+///
+/// `err` may be a `PingPongError` or the `io::Error` the swarm wraps it in.
+pub fn socks_error<'a>(err: &'a (dyn std::error::Error + 'static)) -> Option<&'a SocksError> {
+    if let Some(TransportTimeoutError::Other(EitherError::A(EitherError::A(EitherError::A(err))))) =
+        err.downcast_ref::<PingPongError>()
+    {
+        return socks::find_socks_error(err);
+    }
+    if let Some(inner) = err.downcast_ref::<io::Error>().and_then(|e| e.get_ref()) {
+        if let Some(socks) = socks_error(inner) {
+            return Some(socks);
+        }
+    }
+    socks::find_socks_error(err)
+}
+
 /// Connect to the Tor socks5 proxy socket.
-pub async fn connect_tor_socks_proxy<'a>(dest: impl IntoTargetAddr<'a>, port: u16) -> Result<TcpStream> {
-    let tor_sock = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port));
-    let stream = Socks5Stream::connect(tor_sock, dest).await?;
-    Ok(stream.into_inner())
+pub async fn connect_tor_socks_proxy(
+    dest: &TargetAddr,
+    proxy: &LocalEndpoint,
+    auth: Option<&Credentials>,
+) -> Result<LocalStream, SocksError> {
+    let mut stream = socks::connect_proxy(proxy).await?;
+    socks::connect(&mut stream, dest, auth).await?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{socks::ReplyCode, testing};
+    use libp2p::core::muxing::StreamMuxer;
+    use std::net::Ipv4Addr;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    #[test]
+    fn socks_error_is_reachable_from_transport_error() {
+        let err: io::Error = SocksError::Reply(ReplyCode::OnionRendezvousFailed).into();
+        let err: PingPongError =
+            TransportTimeoutError::Other(EitherError::A(EitherError::A(EitherError::A(err))));
+        // The swarm hands transport errors to us wrapped in an `io::Error`.
+        let err = io::Error::other(err);
+
+        let got = socks_error(&err).and_then(SocksError::reply_code);
+        assert_eq!(got, Some(ReplyCode::OnionRendezvousFailed));
+    }
+
+    /// Stands in for Tor: accepts one SOCKS5 CONNECT and forwards it to the
+    /// onion service at `service_port`, prefixed with `proxy_header`.
+    async fn fake_tor(service_port: u16, proxy_header: &'static [u8]) -> u16 {
+        async fn read_bytes(stream: &mut TcpStream, n: usize) -> Vec<u8> {
+            let mut buf = vec![0u8; n];
+            stream.read_exact(&mut buf).await.unwrap();
+            buf
+        }
+
+        let mut socks = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let socks_port = socks.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let (mut client, _) = socks.accept().await.unwrap();
+            let method = read_bytes(&mut client, 3).await[2];
+            client.write_all(&[5, method]).await.unwrap();
+            if method == 2 {
+                // Username/password, any credentials will do.
+                let len = read_bytes(&mut client, 2).await[1];
+                read_bytes(&mut client, len as usize).await;
+                let len = read_bytes(&mut client, 1).await[0];
+                read_bytes(&mut client, len as usize).await;
+                client.write_all(&[1, 0]).await.unwrap();
+            }
+            let len = read_bytes(&mut client, 5).await[4];
+            read_bytes(&mut client, len as usize + 2).await;
+            client
+                .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+
+            let mut service = TcpStream::connect((Ipv4Addr::LOCALHOST, service_port))
+                .await
+                .unwrap();
+            service.write_all(proxy_header).await.unwrap();
+            let (mut client_rx, mut client_tx) = tokio::io::split(client);
+            let (mut service_rx, mut service_tx) = tokio::io::split(service);
+            let _ = future::join(
+                tokio::io::copy(&mut client_rx, &mut service_tx),
+                tokio::io::copy(&mut service_rx, &mut client_tx),
+            )
+            .await;
+        });
+
+        socks_port
+    }
+
+    #[tokio::test]
+    async fn transport_reports_circuit_id_of_inbound_connections() {
+        let onion: Multiaddr = "/onion3/vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd:7"
+            .parse()
+            .unwrap();
+        let port = free_local_port().unwrap();
+        let mut map = HashMap::new();
+        map.insert(onion.clone(), port);
+
+        let tor = TorTokioTcpConfig::new().onion_map(map).proxy_protocol(true);
+        let listener = build_transport(identity::Keypair::generate_ed25519(), tor).unwrap();
+        let (mut listener, _) = testing::listen(listener, onion.clone()).await;
+
+        let socks_port =
+            fake_tor(port, b"PROXY TCP6 fc00:dead:beef:4dad::2:a ::1 65535 7\r\n").await;
+        let tor = TorTokioTcpConfig::new().socks_port(socks_port);
+        let dialer = build_transport(identity::Keypair::generate_ed25519(), tor).unwrap();
+
+        let (tx, rx) = futures::channel::oneshot::channel();
+        tokio::spawn(async move {
+            let (info, muxer) = testing::next_upgrade(&mut listener)
+                .await
+                .await
+                .expect("inbound upgrade failed");
+            tx.send(info).unwrap();
+            // Reading drives the rest of the protocol negotiation to the dialer.
+            while future::poll_fn(|cx| muxer.poll_inbound(cx)).await.is_ok() {}
+        });
+        let dialed = dialer.dial(onion).unwrap().await;
+        let info = rx.await.unwrap();
+
+        assert_eq!(info.circuit_id(), Some(0x0002_000a));
+        let (info, _) = dialed.expect("dial failed");
+        assert_eq!(info.circuit_id(), None);
+    }
 }
@@ -0,0 +1,161 @@
+//! Limits applied to inbound connections on the onion listener.
+
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Default backlog for the listening socket.
+pub const DEFAULT_BACKLOG: i32 = 1024;
+
+/// Maximum rate at which inbound connections are accepted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AcceptRate {
+    /// Connections accepted per second on average.
+    pub per_second: f64,
+    /// Number of connections that may be accepted in a burst.
+    pub burst: u32,
+}
+
+impl AcceptRate {
+    /// Fails unless `per_second` is finite and positive and `burst` at least
+    /// one, the token bucket would never refill otherwise.
+    pub(crate) fn new(per_second: f64, burst: u32) -> io::Result<Self> {
+        if !(per_second.is_finite() && per_second > 0.0) {
+            let msg = format!(
+                "accept rate must be a positive number per second: {}",
+                per_second
+            );
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        if burst == 0 {
+            let msg = "accept burst must be at least one connection";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        Ok(AcceptRate { per_second, burst })
+    }
+}
+
+/// Token bucket used to smooth the rate of accepted connections.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    rate: AcceptRate,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(rate: AcceptRate) -> Self {
+        TokenBucket {
+            rate,
+            tokens: f64::from(rate.burst),
+            last: Instant::now(),
+        }
+    }
+
+    /// Takes a token, if none is available returns how long until one is.
+    pub(crate) fn take(&mut self) -> Result<(), Duration> {
+        self.take_at(Instant::now())
+    }
+
+    fn take_at(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens =
+            (self.tokens + elapsed * self.rate.per_second).min(f64::from(self.rate.burst));
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+
+        let wait = (1.0 - self.tokens) / self.rate.per_second;
+        Err(Duration::from_secs_f64(wait))
+    }
+}
+
+/// Counts open inbound connections.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ConnectionCounter(Arc<AtomicUsize>);
+
+impl ConnectionCounter {
+    /// Registers a new connection unless there are already `max` open.
+    pub(crate) fn try_acquire(&self, max: usize) -> Option<ConnectionGuard> {
+        let mut current = self.0.load(Ordering::SeqCst);
+        loop {
+            if current >= max {
+                return None;
+            }
+            match self
+                .0
+                .compare_exchange(current, current + 1, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => return Some(ConnectionGuard(self.0.clone())),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    /// Number of open connections.
+    pub(crate) fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Releases its slot in the `ConnectionCounter` when dropped.
+#[derive(Debug)]
+pub(crate) struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_burst_then_throttles() {
+        let mut bucket = TokenBucket::new(AcceptRate {
+            per_second: 2.0,
+            burst: 3,
+        });
+        let now = bucket.last;
+
+        for _ in 0..3 {
+            assert!(bucket.take_at(now).is_ok());
+        }
+        let wait = bucket.take_at(now).expect_err("bucket should be empty");
+        assert_eq!(wait, Duration::from_millis(500));
+
+        assert!(bucket.take_at(now + Duration::from_millis(500)).is_ok());
+    }
+
+    #[test]
+    fn rejects_rates_that_never_refill() {
+        for rate in &[0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(AcceptRate::new(*rate, 1).is_err(), "rate {}", rate);
+        }
+        assert!(AcceptRate::new(1.0, 0).is_err());
+        assert!(AcceptRate::new(0.5, 1).is_ok());
+    }
+
+    #[test]
+    fn counter_enforces_maximum() {
+        let counter = ConnectionCounter::default();
+
+        let a = counter.try_acquire(2).expect("first connection refused");
+        let _b = counter.try_acquire(2).expect("second connection refused");
+        assert!(counter.try_acquire(2).is_none());
+
+        drop(a);
+        assert_eq!(counter.count(), 1);
+        assert!(counter.try_acquire(2).is_some());
+    }
+}
@@ -1,16 +1,35 @@
 #![warn(rust_2018_idioms)]
 #![forbid(unsafe_code)]
+use std::{path::Path, time::Duration};
+
 use anyhow::{Context, Result};
 use log::{warn, Level};
 use structopt::StructOpt;
 
-use ping_pong::{run_dialer, run_listener, Opt};
+use libp2p::{identity, identity::ed25519, PeerId};
+use ping_pong::{
+    add_client_auth,
+    client_auth::ClientAuthKey,
+    control::ControlConfig,
+    keys::{self, IdentitySource},
+    keystore::{Keystore, Passphrase, PassphraseSource},
+    launch::TorLauncher,
+    passphrase_source,
+    peer::{self, PeerPolicy},
+    psk, run_dialer, run_ephemeral_listener, run_listener,
+    service::{EphemeralOnion, KeySource},
+    transport::TorTokioTcpConfig,
+    wait_for_bootstrap, Command, Opt,
+};
+
+/// Name of the listener's onion service key in the keystore.
+const LISTENER_ONION_KEY: &str = "listener";
 
-/// The ping-pong onion service address.
+/// The ping-pong onion service address dialed by default.
 const ONION: &str = "/onion3/r4nttccifklkruvrztwxuhk2iy4xx7cnnex2sgogbo4zw6rnx3cq2bid:7";
 
-/// Tor should be started with a hidden service configured. Must be
-/// re-started for each execution of the listener.
+/// Without a control port Tor should be started with a hidden service
+/// configured, the listener then serves it for as long as Tor runs.
 ///
 /// See torrc for an example, if using that file Tor can be started with:
 ///
@@ -20,23 +39,231 @@ const ONION: &str = "/onion3/r4nttccifklkruvrztwxuhk2iy4xx7cnnex2sgogbo4zw6rnx3c
 ///
 ///   /var/lib/tor/hidden_service/hostname
 ///
-/// Update ONION above before running the listener.
+/// The listener reads its onion services from the torrc (`--torrc`) and
+/// their `hostname` files, and listens on the first one unless `--onion`
+/// is given. Update ONION above, or pass `--onion`, to dial it.
+///
+/// Alternatively, pass `--control-port 127.0.0.1:9051` to have the listener
+/// create an ephemeral onion service itself, no torrc changes required. Add
+/// `--onion-key <file>` to keep the same onion address across restarts.
+///
+/// With `--launch-tor` we start a private Tor ourselves, neither root nor a
+/// running system Tor is needed.
 #[tokio::main]
 async fn main() -> Result<()> {
     simple_logger::init_with_level(Level::Debug).unwrap();
 
     let opt = Opt::from_args();
+    let passphrase = passphrase_source(opt.passphrase_env, opt.passphrase_fd);
+    if let Some(cmd) = opt.cmd {
+        return run_command(cmd, &passphrase);
+    }
+
+    let onion = match &opt.onion {
+        Some(addr) => Some(
+            addr.parse()
+                .with_context(|| format!("failed to parse multiaddr: {}", addr))?,
+        ),
+        None => None,
+    };
+
+    let mut tor = TorTokioTcpConfig::new()
+        .socks_proxy(opt.socks)
+        .isolation(opt.isolation)
+        .tor_resolve(opt.tor_resolve)
+        .allow_clearnet(opt.allow_clearnet)
+        .onion_v2(opt.onion_v2)
+        .proxy_protocol(opt.proxy_protocol)
+        .verify_onion_identity(opt.verify_onion_identity)
+        .security(opt.security)
+        .backlog(opt.backlog)?;
+    if let Some(max) = opt.max_inbound {
+        tor = tor.max_inbound(max)?;
+    }
+    if let Some(rate) = opt.accept_rate {
+        tor = tor.accept_rate(rate, opt.accept_burst)?;
+    }
+    if let Some(path) = &opt.swarm_key {
+        let key = psk::read_swarm_key(path).context("failed to read swarm key")?;
+        tor = tor.private_network(key);
+    }
+    if let Some(path) = &opt.allow_peers {
+        let peers = peer::read_peer_list(path).context("failed to read allowed peers")?;
+        tor = tor.peer_policy(PeerPolicy::Allow(peers));
+    } else if let Some(path) = &opt.deny_peers {
+        let peers = peer::read_peer_list(path).context("failed to read denied peers")?;
+        tor = tor.peer_policy(PeerPolicy::Deny(peers));
+    }
+
+    // Keys from the keystore, generating and saving any that are missing.
+    let mut keystore_onion_key = None;
+    let id_keys = if let Some(path) = &opt.keystore {
+        let (mut keystore, passphrase) = open_keystore(path, &passphrase)?;
+        let mut save = !path.exists();
+        let id_keys = match keystore.identity() {
+            Some(id_keys) => id_keys,
+            None => {
+                let keypair = ed25519::Keypair::generate();
+                keystore.set_identity(keypair.clone());
+                save = true;
+                identity::Keypair::Ed25519(keypair)
+            }
+        };
+        let ephemeral_listener = !opt.dialer && (opt.control_port.is_some() || opt.launch_tor);
+        if ephemeral_listener && opt.onion_key.is_none() && !opt.onion_from_identity {
+            if keystore.onion_key(LISTENER_ONION_KEY).is_none() {
+                keystore.set_onion_key(LISTENER_ONION_KEY, ed25519::Keypair::generate());
+                save = true;
+            }
+            keystore_onion_key = keystore.onion_key(LISTENER_ONION_KEY);
+        }
+        if save {
+            keystore
+                .write(path, &passphrase)
+                .with_context(|| format!("failed to save keystore: {}", path.display()))?;
+        }
+        id_keys
+    } else {
+        let identity = match &opt.identity {
+            Some(path) => IdentitySource::FileOrGenerate(path.clone()),
+            None => IdentitySource::Ephemeral,
+        };
+        identity.keypair().context("failed to load identity")?
+    };
 
-    let addr = opt.onion.unwrap_or_else(|| ONION.to_string());
-    let addr = addr
-        .parse()
-        .with_context(|| format!("failed to parse multiaddr: {}", addr))?;
+    let bootstrap_timeout = Duration::from_secs(opt.bootstrap_timeout);
+
+    // Our own Tor, if any, runs until it is dropped at the end of main.
+    let tor_process = if opt.launch_tor {
+        let launcher = TorLauncher::new()
+            .binary(&opt.tor_binary)
+            .bootstrap_timeout(bootstrap_timeout);
+        Some(launcher.launch().await?)
+    } else {
+        None
+    };
+    if let Some(process) = &tor_process {
+        tor = tor.socks_port(process.socks_port());
+    }
+
+    let control = match (opt.control_port, &tor_process) {
+        (Some(endpoint), _) => {
+            let mut control = ControlConfig::new(endpoint);
+            if let Some(password) = opt.control_password {
+                control = control.password(password);
+            }
+            if let Some(path) = opt.control_cookie {
+                control = control.cookie_file(path);
+            }
+            Some(control)
+        }
+        (None, Some(process)) => Some(process.control_config()),
+        (None, None) => None,
+    };
 
     if opt.dialer {
-        run_dialer(addr).await?;
+        if let Some(control) = &control {
+            wait_for_bootstrap(control, bootstrap_timeout).await?;
+        }
+        let addr = onion.unwrap_or_else(|| ONION.parse().expect("valid multiaddr"));
+        if let Some(path) = opt.client_auth_key {
+            let control = control
+                .as_ref()
+                .context("--client-auth-key requires a control port")?;
+            let key = ClientAuthKey::read(&path)
+                .with_context(|| format!("failed to read client auth key: {}", path.display()))?;
+            add_client_auth(control, &addr, &key).await?;
+        }
+        run_dialer(addr, id_keys, tor).await?;
+    } else if let Some(control) = control {
+        let mut onion = EphemeralOnion::new(control, opt.onion_port)
+            .publish_timeout(Duration::from_secs(opt.publish_timeout));
+        if let Some(key) = keystore_onion_key {
+            onion = onion.key_source(KeySource::Fixed(key));
+        }
+        if let Some(path) = opt.onion_key {
+            onion = onion.key_source(KeySource::File(path));
+        }
+        if opt.onion_from_identity {
+            onion = onion.key_source(KeySource::Identity);
+        }
+        for key in opt.authorized_clients {
+            onion = onion.authorized_client(key);
+        }
+        run_ephemeral_listener(onion, id_keys, tor).await?;
     } else {
-        run_listener(addr).await?;
+        run_listener(&opt.torrc, onion, id_keys, tor).await?;
     }
 
     Ok(())
 }
+
+fn run_command(cmd: Command, passphrase: &PassphraseSource) -> Result<()> {
+    match cmd {
+        Command::ClientAuthKeygen { key_file } => {
+            let key = ClientAuthKey::generate();
+            key.write(&key_file)
+                .with_context(|| format!("failed to write {}", key_file.display()))?;
+            println!("Private key written to: {}", key_file.display());
+            println!("Public key (for --authorized-client): {}", key.public_key());
+        }
+        Command::PeerId { key_file } => {
+            let keypair = IdentitySource::File(key_file.clone())
+                .keypair()
+                .with_context(|| format!("failed to read {}", key_file.display()))?;
+            println!("{}", PeerId::from(keypair.public()));
+        }
+        Command::KeystorePassphrase {
+            keystore: path,
+            new_passphrase_env,
+            new_passphrase_fd,
+        } => {
+            let old = passphrase.read("Current keystore passphrase: ")?;
+            let keystore = Keystore::read(&path, &old)
+                .with_context(|| format!("failed to read keystore: {}", path.display()))?;
+            let new = passphrase_source(new_passphrase_env, new_passphrase_fd)
+                .read_new("New keystore passphrase: ")?;
+            keystore
+                .write(&path, &new)
+                .with_context(|| format!("failed to write keystore: {}", path.display()))?;
+            println!("Passphrase of {} changed", path.display());
+        }
+        Command::KeystoreExport {
+            keystore: path,
+            port,
+        } => {
+            let passphrase = passphrase.read("Keystore passphrase: ")?;
+            let keystore = Keystore::read(&path, &passphrase)
+                .with_context(|| format!("failed to read keystore: {}", path.display()))?;
+            if let Some(id_keys) = keystore.identity() {
+                println!("Peer ID: {}", PeerId::from(id_keys.public()));
+            }
+            for (name, address) in keystore.onion_addresses(port) {
+                println!("Onion service {}: {}", name, address);
+            }
+        }
+        Command::Keygen { dir, port } => {
+            let address = keys::write_hidden_service_dir(&dir, &ed25519::Keypair::generate(), port)
+                .with_context(|| format!("failed to write {}", dir.display()))?;
+            println!("Onion service keys written to: {}", dir.display());
+            println!("Hostname: {}", address.host());
+            println!("Multiaddr: {}", address);
+        }
+    }
+
+    Ok(())
+}
+
+/// Opens the keystore at `path`, or creates an empty one if it does not
+/// exist, asking for a new passphrase.
+fn open_keystore(path: &Path, source: &PassphraseSource) -> Result<(Keystore, Passphrase)> {
+    if !path.exists() {
+        let passphrase = source.read_new("New keystore passphrase: ")?;
+        return Ok((Keystore::new(), passphrase));
+    }
+
+    let passphrase = source.read("Keystore passphrase: ")?;
+    let keystore = Keystore::read(path, &passphrase)
+        .with_context(|| format!("failed to read keystore: {}", path.display()))?;
+    Ok((keystore, passphrase))
+}
@@ -0,0 +1,359 @@
+//! Onion service addresses.
+//!
+//! A v3 onion address is the base32 encoding of `PUBKEY | CHECKSUM | VERSION`
+//! where `CHECKSUM = SHA3-256(".onion checksum" | PUBKEY | VERSION)[..2]`, see
+//! Tor's rend-spec-v3.txt. We check both the checksum and the version before
+//! handing an address to Tor so that typos fail immediately.
+
+use std::{borrow::Cow, convert::TryFrom, error::Error, fmt, io, str::FromStr};
+
+use data_encoding::BASE32;
+use libp2p::{
+    core::{identity, multiaddr::Protocol},
+    Multiaddr,
+};
+use sha2::Sha512;
+use sha3::{Digest, Sha3_256};
+
+/// Length of an ed25519 public key.
+pub const PUBKEY_LEN: usize = 32;
+
+/// The only onion service version we support.
+pub const V3_VERSION: u8 = 0x03;
+
+const CHECKSUM_PREFIX: &[u8] = b".onion checksum";
+const V3_ADDR_LEN: usize = PUBKEY_LEN + 2 + 1;
+
+/// An onion service address and port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OnionAddress {
+    /// Deprecated v2 onion service, identified by a truncated RSA key hash.
+    V2 {
+        /// First 10 bytes of the SHA1 hash of the service's RSA key.
+        hash: [u8; 10],
+        /// Virtual port.
+        port: u16,
+    },
+    /// v3 onion service, identified by an ed25519 public key.
+    V3 {
+        /// The service's ed25519 public key.
+        public_key: [u8; PUBKEY_LEN],
+        /// Virtual port.
+        port: u16,
+    },
+}
+
+impl OnionAddress {
+    /// Creates a v3 address for the service with `public_key`.
+    pub fn v3(public_key: [u8; PUBKEY_LEN], port: u16) -> Self {
+        OnionAddress::V3 { public_key, port }
+    }
+
+    /// The v3 address of the onion service whose key is the libp2p identity
+    /// `key`, `None` unless `key` is an ed25519 key.
+    pub fn from_public_key(key: &identity::PublicKey, port: u16) -> Option<Self> {
+        match key {
+            identity::PublicKey::Ed25519(key) => Some(OnionAddress::v3(key.encode(), port)),
+            _ => None,
+        }
+    }
+
+    /// The service's key as a libp2p identity, `None` for v2 addresses.
+    pub fn to_public_key(&self) -> Option<identity::PublicKey> {
+        match self {
+            OnionAddress::V2 { .. } => None,
+            OnionAddress::V3 { public_key, .. } => identity::ed25519::PublicKey::decode(public_key)
+                .ok()
+                .map(identity::PublicKey::Ed25519),
+        }
+    }
+
+    /// Host name Tor expects in a SOCKS request, of form: ADDR.onion
+    pub fn host(&self) -> String {
+        let encoded = match self {
+            OnionAddress::V2 { hash, .. } => BASE32.encode(hash),
+            OnionAddress::V3 { public_key, .. } => BASE32.encode(&v3_address_bytes(public_key)),
+        };
+        format!("{}.onion", encoded.to_lowercase())
+    }
+
+    /// The virtual port.
+    pub fn port(&self) -> u16 {
+        match self {
+            OnionAddress::V2 { port, .. } | OnionAddress::V3 { port, .. } => *port,
+        }
+    }
+
+    /// The multiaddr protocol for this address.
+    pub fn to_protocol(&self) -> Protocol<'static> {
+        match self {
+            OnionAddress::V2 { hash, port } => Protocol::Onion(Cow::Owned(*hash), *port),
+            OnionAddress::V3 { public_key, port } => {
+                Protocol::Onion3((v3_address_bytes(public_key), *port).into())
+            }
+        }
+    }
+}
+
+impl fmt::Display for OnionAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_protocol())
+    }
+}
+
+impl<'a> TryFrom<&Protocol<'a>> for OnionAddress {
+    type Error = OnionAddressError;
+
+    fn try_from(proto: &Protocol<'a>) -> Result<Self, Self::Error> {
+        match proto {
+            Protocol::Onion(hash, port) => Ok(OnionAddress::V2 {
+                hash: **hash,
+                port: *port,
+            }),
+            Protocol::Onion3(addr) => {
+                let public_key = parse_v3_address(addr.hash())?;
+                Ok(OnionAddress::V3 {
+                    public_key,
+                    port: addr.port(),
+                })
+            }
+            _ => Err(OnionAddressError::NotOnion),
+        }
+    }
+}
+
+/// Parses a v3 host name, with or without the `.onion` suffix.
+impl FromStr for OnionAddress {
+    type Err = OnionAddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = match s.rfind(':') {
+            Some(i) => {
+                let port = s[i + 1..]
+                    .parse()
+                    .map_err(|_| OnionAddressError::InvalidEncoding)?;
+                (&s[..i], port)
+            }
+            None => (s, 0),
+        };
+        let host = host.trim_end_matches(".onion").to_uppercase();
+        let bytes = BASE32
+            .decode(host.as_bytes())
+            .map_err(|_| OnionAddressError::InvalidEncoding)?;
+        if bytes.len() != V3_ADDR_LEN {
+            return Err(OnionAddressError::InvalidEncoding);
+        }
+
+        let mut raw = [0u8; V3_ADDR_LEN];
+        raw.copy_from_slice(&bytes);
+        Ok(OnionAddress::v3(parse_v3_address(&raw)?, port))
+    }
+}
+
+/// Errors from parsing or validating an onion address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnionAddressError {
+    /// Not an `/onion` or `/onion3` address.
+    NotOnion,
+    /// The address is not valid base32 or has the wrong length.
+    InvalidEncoding,
+    /// The version byte is not 0x03.
+    UnsupportedVersion(u8),
+    /// The checksum does not match, most likely a typo.
+    BadChecksum,
+    /// v2 onion addresses are rejected by policy.
+    V2Rejected,
+}
+
+impl fmt::Display for OnionAddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OnionAddressError::NotOnion => write!(f, "not an onion address"),
+            OnionAddressError::InvalidEncoding => write!(f, "invalid onion address encoding"),
+            OnionAddressError::UnsupportedVersion(v) => {
+                write!(f, "unsupported onion address version: {}", v)
+            }
+            OnionAddressError::BadChecksum => write!(f, "onion address checksum mismatch"),
+            OnionAddressError::V2Rejected => write!(f, "v2 onion addresses are not allowed"),
+        }
+    }
+}
+
+impl Error for OnionAddressError {}
+
+impl From<OnionAddressError> for io::Error {
+    fn from(e: OnionAddressError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }
+}
+
+/// What to do when asked to dial a deprecated v2 `/onion` address.
+///
+/// Tor removed support for v2 onion services in 0.4.6.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnionV2Policy {
+    /// Refuse to dial v2 addresses.
+    Reject,
+    /// Dial v2 addresses but log a warning.
+    #[default]
+    Warn,
+    /// Dial v2 addresses silently.
+    Allow,
+}
+
+impl FromStr for OnionV2Policy {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(OnionV2Policy::Reject),
+            "warn" => Ok(OnionV2Policy::Warn),
+            "allow" => Ok(OnionV2Policy::Allow),
+            _ => {
+                let msg = format!(
+                    "invalid v2 onion policy (want reject, warn or allow): {}",
+                    s
+                );
+                Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+            }
+        }
+    }
+}
+
+/// The `/onion3` multiaddr of the onion service whose key is the libp2p
+/// identity `key`, `None` unless `key` is an ed25519 key.
+pub fn onion_multiaddr(key: &identity::PublicKey, port: u16) -> Option<Multiaddr> {
+    let addr = OnionAddress::from_public_key(key, port)?;
+    Some(Multiaddr::empty().with(addr.to_protocol()))
+}
+
+/// Expands an ed25519 secret key (seed) into the form Tor stores, the
+/// SHA-512 hash of the seed with the scalar half clamped.
+pub fn expand_secret_key(secret: &[u8; PUBKEY_LEN]) -> [u8; 64] {
+    let hash = Sha512::digest(secret);
+    let mut expanded = [0u8; 64];
+    expanded.copy_from_slice(&hash);
+    expanded[0] &= 248;
+    expanded[31] &= 63;
+    expanded[31] |= 64;
+    expanded
+}
+
+/// The v3 checksum for `public_key`.
+pub fn v3_checksum(public_key: &[u8; PUBKEY_LEN]) -> [u8; 2] {
+    let mut hasher = Sha3_256::new();
+    hasher.input(CHECKSUM_PREFIX);
+    hasher.input(public_key);
+    hasher.input([V3_VERSION]);
+    let hash = hasher.result();
+    [hash[0], hash[1]]
+}
+
+// Encodes `PUBKEY | CHECKSUM | VERSION`.
+fn v3_address_bytes(public_key: &[u8; PUBKEY_LEN]) -> [u8; V3_ADDR_LEN] {
+    let mut raw = [0u8; V3_ADDR_LEN];
+    raw[..PUBKEY_LEN].copy_from_slice(public_key);
+    raw[PUBKEY_LEN..PUBKEY_LEN + 2].copy_from_slice(&v3_checksum(public_key));
+    raw[V3_ADDR_LEN - 1] = V3_VERSION;
+    raw
+}
+
+// Checks the version and checksum, returns the public key.
+fn parse_v3_address(raw: &[u8; V3_ADDR_LEN]) -> Result<[u8; PUBKEY_LEN], OnionAddressError> {
+    let version = raw[V3_ADDR_LEN - 1];
+    if version != V3_VERSION {
+        return Err(OnionAddressError::UnsupportedVersion(version));
+    }
+
+    let mut public_key = [0u8; PUBKEY_LEN];
+    public_key.copy_from_slice(&raw[..PUBKEY_LEN]);
+    if raw[PUBKEY_LEN..PUBKEY_LEN + 2] != v3_checksum(&public_key) {
+        return Err(OnionAddressError::BadChecksum);
+    }
+
+    Ok(public_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::Multiaddr;
+
+    const VALID: &str = "vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd";
+
+    fn onion3(host: &str) -> Result<OnionAddress, OnionAddressError> {
+        let multi: Multiaddr = format!("/onion3/{}:7", host)
+            .parse()
+            .expect("failed to parse");
+        let proto = multi.iter().next().unwrap();
+        OnionAddress::try_from(&proto)
+    }
+
+    #[test]
+    fn accepts_valid_v3_address() {
+        let addr = onion3(VALID).expect("valid address rejected");
+
+        assert_eq!(addr.host(), format!("{}.onion", VALID));
+        assert_eq!(addr.port(), 7);
+    }
+
+    #[test]
+    fn rejects_bad_checksum() {
+        // Single character typo.
+        let typo = VALID.replacen("vww6", "vxw6", 1);
+
+        assert_eq!(onion3(&typo), Err(OnionAddressError::BadChecksum));
+    }
+
+    #[test]
+    fn rejects_bad_version() {
+        // Last character encodes the low bits of the version byte.
+        let typo = format!("{}c", &VALID[..VALID.len() - 1]);
+
+        assert_eq!(
+            onion3(&typo),
+            Err(OnionAddressError::UnsupportedVersion(0x02))
+        );
+    }
+
+    #[test]
+    fn can_parse_host_name() {
+        let want = onion3(VALID).unwrap();
+        let got: OnionAddress = format!("{}.onion:7", VALID).parse().unwrap();
+
+        assert_eq!(got, want);
+    }
+
+    #[test]
+    fn can_convert_to_multiaddr() {
+        let addr = onion3(VALID).unwrap();
+
+        assert_eq!(addr.to_string(), format!("/onion3/{}:7", VALID));
+    }
+
+    #[test]
+    fn identity_key_is_onion_key() {
+        let keypair = identity::Keypair::generate_ed25519();
+        let public = keypair.public();
+        let multi = onion_multiaddr(&public, 7).unwrap();
+        let addr = OnionAddress::try_from(&multi.iter().next().unwrap()).unwrap();
+
+        assert_eq!(addr.to_public_key(), Some(public));
+        assert_eq!(addr.port(), 7);
+    }
+
+    #[test]
+    fn expanded_secret_key_matches_public_key() {
+        use curve25519_dalek::{constants::ED25519_BASEPOINT_TABLE, scalar::Scalar};
+
+        let keypair = identity::ed25519::Keypair::generate();
+        let mut secret = [0u8; PUBKEY_LEN];
+        secret.copy_from_slice(keypair.secret().as_ref());
+        let expanded = expand_secret_key(&secret);
+
+        let mut scalar = [0u8; 32];
+        scalar.copy_from_slice(&expanded[..32]);
+        let point = &Scalar::from_bits(scalar) * &ED25519_BASEPOINT_TABLE;
+        assert_eq!(point.compress().to_bytes(), keypair.public().encode());
+    }
+}
@@ -0,0 +1,266 @@
+//! Checks applied to the remote peer once it has been authenticated.
+
+use std::{collections::HashSet, convert::TryFrom, error::Error, fmt, fs, io, path::Path};
+
+use libp2p::{
+    core::{multiaddr::Protocol, ConnectedPoint},
+    Multiaddr, PeerId,
+};
+use log::warn;
+
+use crate::onion::OnionAddress;
+
+/// Reasons for rejecting an authenticated peer.
+#[derive(Debug)]
+pub enum PeerError {
+    /// The dialed address named a different peer than the one we reached.
+    Mismatch {
+        /// `PeerId` from the `/p2p` component of the dialed address.
+        expected: PeerId,
+        /// `PeerId` the remote authenticated as.
+        actual: PeerId,
+    },
+    /// The peer's identity is not the key of the dialed onion service.
+    OnionMismatch {
+        /// The dialed onion service.
+        onion: OnionAddress,
+        /// `PeerId` the remote authenticated as.
+        actual: PeerId,
+    },
+    /// The peer policy does not permit the peer.
+    Rejected(PeerId),
+}
+
+impl fmt::Display for PeerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerError::Mismatch { expected, actual } => write!(
+                f,
+                "remote authenticated as {} but we expected {}",
+                actual, expected
+            ),
+            PeerError::OnionMismatch { onion, actual } => write!(
+                f,
+                "remote authenticated as {} which is not the identity of {}",
+                actual, onion
+            ),
+            PeerError::Rejected(peer) => {
+                write!(f, "peer {} is not permitted by our peer policy", peer)
+            }
+        }
+    }
+}
+
+impl Error for PeerError {}
+
+/// Which authenticated peers may connect to us, checked before any
+/// protocol runs on the connection.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum PeerPolicy {
+    /// Any peer.
+    #[default]
+    Open,
+    /// Only the listed peers.
+    Allow(HashSet<PeerId>),
+    /// All but the listed peers.
+    Deny(HashSet<PeerId>),
+}
+
+impl PeerPolicy {
+    /// Whether `peer` may connect.
+    pub fn permits(&self, peer: &PeerId) -> bool {
+        match self {
+            PeerPolicy::Open => true,
+            PeerPolicy::Allow(peers) => peers.contains(peer),
+            PeerPolicy::Deny(peers) => !peers.contains(peer),
+        }
+    }
+
+    /// Verifies that, when listening, the authenticated `peer` may connect,
+    /// logging it if not. Peers we dial are always permitted.
+    #[allow(clippy::result_large_err)]
+    pub fn verify(&self, endpoint: &ConnectedPoint, peer: &PeerId) -> Result<(), PeerError> {
+        let remote = match endpoint {
+            ConnectedPoint::Dialer { .. } => return Ok(()),
+            ConnectedPoint::Listener { send_back_addr, .. } => send_back_addr,
+        };
+        if self.permits(peer) {
+            return Ok(());
+        }
+        warn!(
+            "closing connection to {} at {}: not permitted by peer policy",
+            peer, remote
+        );
+        Err(PeerError::Rejected(peer.clone()))
+    }
+}
+
+/// Reads a list of `PeerId`s for a `PeerPolicy`, see [`parse_peer_list`].
+pub fn read_peer_list(path: &Path) -> io::Result<HashSet<PeerId>> {
+    parse_peer_list(&fs::read_to_string(path)?).map_err(|e| {
+        let msg = format!("{}: {}", path.display(), e);
+        io::Error::new(e.kind(), msg)
+    })
+}
+
+/// Parses a list of `PeerId`s, one per line. Blank lines and `#` comments
+/// are ignored.
+pub fn parse_peer_list(list: &str) -> io::Result<HashSet<PeerId>> {
+    let mut peers = HashSet::new();
+
+    for (i, line) in list.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let peer = line.parse().map_err(|_| {
+            let msg = format!("line {}: invalid PeerId: {}", i + 1, line);
+            io::Error::new(io::ErrorKind::InvalidData, msg)
+        })?;
+        peers.insert(peer);
+    }
+
+    Ok(peers)
+}
+
+/// The `PeerId` from a trailing `/p2p` component of `addr`, if any.
+pub fn expected_peer_id(addr: &Multiaddr) -> Option<PeerId> {
+    match addr.iter().last()? {
+        Protocol::P2p(hash) => PeerId::from_multihash(hash).ok(),
+        _ => None,
+    }
+}
+
+/// Verifies that, when dialing, the authenticated `peer` is the one named
+/// by the dialed address. Addresses without `/p2p` accept any peer.
+#[allow(clippy::result_large_err)]
+pub fn verify(endpoint: &ConnectedPoint, peer: &PeerId) -> Result<(), PeerError> {
+    let address = match endpoint {
+        ConnectedPoint::Dialer { address } => address,
+        ConnectedPoint::Listener { .. } => return Ok(()),
+    };
+
+    match expected_peer_id(address) {
+        Some(expected) if expected != *peer => Err(PeerError::Mismatch {
+            expected,
+            actual: peer.clone(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Verifies that, when dialing an onion service, the authenticated `peer`
+/// is the one whose identity key is the onion service's key, see
+/// `onion::onion_multiaddr`. Non-onion addresses accept any peer.
+#[allow(clippy::result_large_err)]
+pub fn verify_onion_identity(endpoint: &ConnectedPoint, peer: &PeerId) -> Result<(), PeerError> {
+    let address = match endpoint {
+        ConnectedPoint::Dialer { address } => address,
+        ConnectedPoint::Listener { .. } => return Ok(()),
+    };
+    let onion = match address.iter().find_map(|p| OnionAddress::try_from(&p).ok()) {
+        Some(onion) => onion,
+        None => return Ok(()),
+    };
+
+    match onion.to_public_key() {
+        Some(key) if peer.is_public_key(&key) == Some(true) => Ok(()),
+        _ => Err(PeerError::OnionMismatch {
+            onion,
+            actual: peer.clone(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity::Keypair;
+
+    fn dialer(peer: &PeerId) -> ConnectedPoint {
+        let address = format!(
+            "/onion3/vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd:7/p2p/{}",
+            peer
+        );
+        ConnectedPoint::Dialer {
+            address: address.parse().expect("failed to parse multiaddr"),
+        }
+    }
+
+    #[test]
+    fn accepts_expected_peer() {
+        let peer = PeerId::from(Keypair::generate_ed25519().public());
+
+        assert!(verify(&dialer(&peer), &peer).is_ok());
+    }
+
+    #[test]
+    fn rejects_unexpected_peer() {
+        let expected = PeerId::from(Keypair::generate_ed25519().public());
+        let actual = PeerId::from(Keypair::generate_ed25519().public());
+
+        assert!(verify(&dialer(&expected), &actual).is_err());
+    }
+
+    #[test]
+    fn accepts_any_peer_without_p2p() {
+        let peer = PeerId::from(Keypair::generate_ed25519().public());
+        let endpoint = ConnectedPoint::Dialer {
+            address: "/onion3/vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd:7"
+                .parse()
+                .unwrap(),
+        };
+
+        assert!(verify(&endpoint, &peer).is_ok());
+    }
+
+    #[test]
+    fn onion_identity_binds_peer_to_address() {
+        let keypair = Keypair::generate_ed25519();
+        let peer = PeerId::from(keypair.public());
+        let other = PeerId::from(Keypair::generate_ed25519().public());
+        let endpoint = ConnectedPoint::Dialer {
+            address: crate::onion::onion_multiaddr(&keypair.public(), 7).unwrap(),
+        };
+
+        assert!(verify_onion_identity(&endpoint, &peer).is_ok());
+        assert!(verify_onion_identity(&endpoint, &other).is_err());
+    }
+
+    #[test]
+    fn policy_permits_listed_peers() {
+        let listed = PeerId::from(Keypair::generate_ed25519().public());
+        let other = PeerId::from(Keypair::generate_ed25519().public());
+        let peers: HashSet<_> = vec![listed.clone()].into_iter().collect();
+
+        assert!(PeerPolicy::Open.permits(&other));
+        assert!(PeerPolicy::Allow(peers.clone()).permits(&listed));
+        assert!(!PeerPolicy::Allow(peers.clone()).permits(&other));
+        assert!(!PeerPolicy::Deny(peers.clone()).permits(&listed));
+        assert!(PeerPolicy::Deny(peers.clone()).permits(&other));
+
+        let policy = PeerPolicy::Deny(peers);
+        let endpoint = ConnectedPoint::Listener {
+            local_addr: "/ip4/127.0.0.1/tcp/7".parse().unwrap(),
+            send_back_addr: "/ip4/127.0.0.1/tcp/4000".parse().unwrap(),
+        };
+        assert!(matches!(
+            policy.verify(&endpoint, &listed),
+            Err(PeerError::Rejected(peer)) if peer == listed
+        ));
+        assert!(policy.verify(&dialer(&listed), &listed).is_ok());
+    }
+
+    #[test]
+    fn parses_peer_list() {
+        let peer = PeerId::from(Keypair::generate_ed25519().public());
+        let list = format!("# Trusted dialers\n\n{}  # laptop\n", peer);
+
+        let peers = parse_peer_list(&list).unwrap();
+        assert_eq!(peers.len(), 1);
+        assert!(peers.contains(&peer));
+
+        let err = parse_peer_list("not-a-peer-id\n").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
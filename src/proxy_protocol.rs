@@ -0,0 +1,223 @@
+//! HAProxy PROXY protocol (version 1) support for inbound connections.
+//!
+//! With `HiddenServiceExportCircuitID haproxy` Tor prefixes every connection
+//! it forwards to an onion service with a PROXY header of the form
+//!
+//!   PROXY TCP6 fc00:dead:beef:4dad::AABB:CCDD ::1 65535 VIRTPORT\r\n
+//!
+//! where `0xAABBCCDD` is the global identifier of the client's circuit.
+
+use std::{
+    fmt, io,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use futures::future::{self, Either};
+use futures_timer::Delay;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Maximum length of a v1 header, including the trailing CRLF.
+const MAX_HEADER_LEN: usize = 107;
+
+/// How long we wait for Tor to send the header after accepting a connection.
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The /64 prefix Tor uses to encode circuit IDs in the source address.
+const CIRCUIT_ID_PREFIX: [u16; 4] = [0xfc00, 0xdead, 0xbeef, 0x4dad];
+
+/// A parsed PROXY v1 header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyHeader {
+    /// The proxied connection's source and destination.
+    Tcp {
+        /// Address of the client, for Tor this encodes the circuit ID.
+        source: SocketAddr,
+        /// Address the client connected to.
+        destination: SocketAddr,
+    },
+    /// The proxy did not know the connection's addresses.
+    Unknown,
+}
+
+impl ProxyHeader {
+    /// The Tor circuit ID, if the source address encodes one.
+    pub fn circuit_id(&self) -> Option<u32> {
+        match self {
+            ProxyHeader::Tcp {
+                source: SocketAddr::V6(source),
+                ..
+            } => circuit_id_from_ip(source.ip()),
+            _ => None,
+        }
+    }
+}
+
+/// Errors reading a PROXY header.
+#[derive(Debug)]
+pub enum ProxyHeaderError {
+    /// I/O error reading from the connection.
+    Io(io::Error),
+    /// The header did not arrive in time.
+    Timeout,
+    /// The header is malformed.
+    Malformed(&'static str),
+}
+
+impl fmt::Display for ProxyHeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyHeaderError::Io(e) => write!(f, "failed to read PROXY header: {}", e),
+            ProxyHeaderError::Timeout => write!(f, "timed out waiting for PROXY header"),
+            ProxyHeaderError::Malformed(msg) => write!(f, "malformed PROXY header: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ProxyHeaderError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProxyHeaderError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ProxyHeaderError> for io::Error {
+    fn from(e: ProxyHeaderError) -> Self {
+        let kind = match &e {
+            ProxyHeaderError::Io(e) => e.kind(),
+            ProxyHeaderError::Timeout => io::ErrorKind::TimedOut,
+            ProxyHeaderError::Malformed(_) => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+/// Reads a PROXY v1 header from the start of `stream`, giving up after `timeout`.
+///
+/// Reads byte by byte so that none of the data following the header is consumed.
+pub async fn read_header<S>(
+    stream: &mut S,
+    timeout: Duration,
+) -> Result<ProxyHeader, ProxyHeaderError>
+where
+    S: AsyncRead + Unpin,
+{
+    let read = Box::pin(read_line(stream));
+    let line = match future::select(read, Delay::new(timeout)).await {
+        Either::Left((line, _)) => line?,
+        Either::Right(_) => return Err(ProxyHeaderError::Timeout),
+    };
+    parse(&line)
+}
+
+async fn read_line<S>(stream: &mut S) -> Result<String, ProxyHeaderError>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut line = Vec::with_capacity(MAX_HEADER_LEN);
+    let mut byte = [0u8; 1];
+    while !line.ends_with(b"\r\n") {
+        if line.len() == MAX_HEADER_LEN {
+            return Err(ProxyHeaderError::Malformed("header too long"));
+        }
+        stream
+            .read_exact(&mut byte)
+            .await
+            .map_err(ProxyHeaderError::Io)?;
+        line.push(byte[0]);
+    }
+    line.truncate(line.len() - 2);
+
+    String::from_utf8(line).map_err(|_| ProxyHeaderError::Malformed("not ASCII"))
+}
+
+/// Parses a header line without the trailing CRLF.
+pub fn parse(line: &str) -> Result<ProxyHeader, ProxyHeaderError> {
+    let mut fields = line.split(' ');
+    if fields.next() != Some("PROXY") {
+        return Err(ProxyHeaderError::Malformed("missing PROXY signature"));
+    }
+
+    match fields.next() {
+        Some("TCP4") | Some("TCP6") => {}
+        Some("UNKNOWN") => return Ok(ProxyHeader::Unknown),
+        _ => return Err(ProxyHeaderError::Malformed("unknown protocol")),
+    }
+
+    let mut next = |what| fields.next().ok_or(ProxyHeaderError::Malformed(what));
+    let src_ip: IpAddr = next("missing source address")?
+        .parse()
+        .map_err(|_| ProxyHeaderError::Malformed("invalid source address"))?;
+    let dst_ip: IpAddr = next("missing destination address")?
+        .parse()
+        .map_err(|_| ProxyHeaderError::Malformed("invalid destination address"))?;
+    let src_port: u16 = next("missing source port")?
+        .parse()
+        .map_err(|_| ProxyHeaderError::Malformed("invalid source port"))?;
+    let dst_port: u16 = next("missing destination port")?
+        .parse()
+        .map_err(|_| ProxyHeaderError::Malformed("invalid destination port"))?;
+    if fields.next().is_some() {
+        return Err(ProxyHeaderError::Malformed("trailing data"));
+    }
+
+    Ok(ProxyHeader::Tcp {
+        source: SocketAddr::new(src_ip, src_port),
+        destination: SocketAddr::new(dst_ip, dst_port),
+    })
+}
+
+/// Extracts the Tor circuit ID from an address of form `fc00:dead:beef:4dad::/64`.
+pub fn circuit_id_from_ip(ip: &Ipv6Addr) -> Option<u32> {
+    let segments = ip.segments();
+    if segments[..4] != CIRCUIT_ID_PREFIX || segments[4..6] != [0, 0] {
+        return None;
+    }
+    Some((u32::from(segments[6]) << 16) | u32::from(segments[7]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_parse_tor_header() {
+        let header = parse("PROXY TCP6 fc00:dead:beef:4dad::1:2a ::1 65535 7").unwrap();
+
+        assert_eq!(header.circuit_id(), Some(0x0001_002a));
+    }
+
+    #[test]
+    fn can_parse_unknown_header() {
+        let header = parse("PROXY UNKNOWN").unwrap();
+
+        assert_eq!(header, ProxyHeader::Unknown);
+        assert_eq!(header.circuit_id(), None);
+    }
+
+    #[test]
+    fn rejects_malformed_header() {
+        assert!(parse("GET / HTTP/1.1").is_err());
+        assert!(parse("PROXY TCP4 1.2.3.4 5.6.7.8 80").is_err());
+        assert!(parse("PROXY TCP4 1.2.3.4 5.6.7.8 80 not-a-port").is_err());
+    }
+
+    #[test]
+    fn can_extract_circuit_id_from_ip() {
+        let ip = "fc00:dead:beef:4dad::ffff:ffff".parse().unwrap();
+
+        assert_eq!(circuit_id_from_ip(&ip), Some(u32::MAX));
+        assert_eq!(circuit_id_from_ip(&"::1".parse().unwrap()), None);
+    }
+
+    #[tokio::test]
+    async fn does_not_read_past_header() {
+        let mut input: &[u8] = b"PROXY TCP6 fc00:dead:beef:4dad::0:7 ::1 65535 7\r\nhello";
+        let header = read_header(&mut input, HEADER_TIMEOUT).await.unwrap();
+
+        assert_eq!(header.circuit_id(), Some(7));
+        assert_eq!(input, b"hello");
+    }
+}
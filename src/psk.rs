@@ -0,0 +1,243 @@
+//! Private networks, only peers holding the swarm key can connect.
+//!
+//! libp2p's pnet encrypts each connection with XSalsa20 under a pre-shared
+//! key, before any libp2p protocol runs. It does not authenticate: a peer
+//! with another key, or none, simply reads garbage, and negotiating the
+//! security protocol stalls until the transport times out. To fail fast
+//! with a distinct error we check that the first bytes we decrypt start
+//! the multistream-select header every libp2p peer sends first.
+
+use std::{
+    cmp, error, fmt, fs, io,
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::prelude::*;
+use libp2p::pnet::{PnetConfig, PnetError, PnetOutput, PreSharedKey};
+
+/// What every peer sends first, after a varint length prefix.
+const MULTISTREAM_PREFIX: &[u8] = b"/multistream";
+
+/// Number of bytes checked, the length prefix and `MULTISTREAM_PREFIX`.
+const CHECKED_LEN: usize = 1 + MULTISTREAM_PREFIX.len();
+
+/// Reads a swarm key in the `/key/swarm/psk/1.0.0/` format, as used by
+/// go-libp2p and IPFS.
+pub fn read_swarm_key(path: &Path) -> io::Result<PreSharedKey> {
+    fs::read_to_string(path)?.parse().map_err(|e| {
+        let msg = format!("{}: invalid swarm key: {}", path.display(), e);
+        io::Error::new(io::ErrorKind::InvalidData, msg)
+    })
+}
+
+/// Runs the pnet handshake on `socket` with `key`.
+pub async fn handshake<S>(key: PreSharedKey, socket: S) -> Result<PskStream<S>, PnetError>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let inner = PnetConfig::new(key).handshake(socket).await?;
+    Ok(PskStream { inner, checked: 0 })
+}
+
+/// The remote does not hold our swarm key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PskMismatch;
+
+impl fmt::Display for PskMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "remote is not a member of our private network (swarm key mismatch)"
+        )
+    }
+}
+
+impl error::Error for PskMismatch {}
+
+impl From<PskMismatch> for io::Error {
+    fn from(e: PskMismatch) -> Self {
+        io::Error::new(io::ErrorKind::PermissionDenied, e)
+    }
+}
+
+/// Whether `err` reports a `PskMismatch`.
+pub fn is_psk_mismatch(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|e| e.is::<PskMismatch>())
+}
+
+/// A pnet encrypted connection that fails reads with `PskMismatch` unless
+/// the remote's first bytes decrypt to a multistream-select header.
+pub struct PskStream<S> {
+    inner: PnetOutput<S>,
+    /// Number of bytes read and checked so far.
+    checked: usize,
+}
+
+impl<S> fmt::Debug for PskStream<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PskStream")
+            .field("checked", &self.checked)
+            .finish()
+    }
+}
+
+impl<S> PskStream<S> {
+    /// A peer without our key closes the connection once it reads garbage,
+    /// so the connection closing before the remote's header arrived is
+    /// reported as a `PskMismatch`.
+    fn closed(&self, err: io::Error) -> io::Error {
+        match err.kind() {
+            io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::UnexpectedEof
+                if self.checked < CHECKED_LEN =>
+            {
+                PskMismatch.into()
+            }
+            _ => err,
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for PskStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let n = match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(n)) => n,
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(self.closed(e))),
+            Poll::Pending => return Poll::Pending,
+        };
+        if self.checked == CHECKED_LEN {
+            return Poll::Ready(Ok(n));
+        }
+        if n == 0 {
+            return Poll::Ready(Err(PskMismatch.into()));
+        }
+
+        let end = cmp::min(CHECKED_LEN, self.checked + n);
+        for (i, byte) in (self.checked..end).zip(&buf[..n]) {
+            // Skip the length prefix.
+            if i > 0 && *byte != MULTISTREAM_PREFIX[i - 1] {
+                return Poll::Ready(Err(PskMismatch.into()));
+            }
+        }
+        self.checked = end;
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for PskStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner)
+            .poll_write(cx, buf)
+            .map_err(|e| self.closed(e))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner)
+            .poll_flush(cx)
+            .map_err(|e| self.closed(e))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        security::{self, HandshakeError, Security},
+        testing,
+    };
+    use libp2p::{
+        core::{either::EitherError, transport::MemoryTransport},
+        identity, Transport,
+    };
+    use std::time::Duration;
+
+    const SWARM_KEY: &str = "/key/swarm/psk/1.0.0/\n/base16/\n\
+        6189c5cf0b87fb800c1a9feeda73c6ab5e998db48fb9e6a978575c770ceef683\n";
+
+    /// Dials a listener over an in-memory transport, each in the private
+    /// network of the given key, and returns both handshake results.
+    async fn connect(
+        dialer: Option<PreSharedKey>,
+        listener: Option<PreSharedKey>,
+    ) -> (Result<(), HandshakeError>, Result<(), HandshakeError>) {
+        let transport = |psk| {
+            let upgrade = Security::Noise
+                .upgrade(identity::Keypair::generate_ed25519())
+                .unwrap();
+            MemoryTransport.and_then(move |conn, endpoint| {
+                security::secure(conn, endpoint, psk, upgrade).map_ok(|_| ())
+            })
+        };
+        let connect = testing::connect_memory(transport(dialer), transport(listener));
+        let (dialed, accepted) = tokio::time::timeout(Duration::from_secs(5), connect)
+            .await
+            .expect("handshake did not fail fast");
+        (handshake_result(dialed), handshake_result(accepted))
+    }
+
+    fn handshake_result<E: fmt::Debug>(
+        res: Result<(), EitherError<E, HandshakeError>>,
+    ) -> Result<(), HandshakeError> {
+        match res {
+            Err(EitherError::A(e)) => panic!("memory transport failed: {:?}", e),
+            Err(EitherError::B(e)) => Err(e),
+            Ok(()) => Ok(()),
+        }
+    }
+
+    fn random_key() -> PreSharedKey {
+        PreSharedKey::new(rand::random())
+    }
+
+    #[tokio::test]
+    async fn peers_with_the_same_key_connect() {
+        let key = random_key();
+        let (dialed, accepted) = connect(Some(key), Some(key)).await;
+
+        assert!(dialed.is_ok());
+        assert!(accepted.is_ok());
+    }
+
+    #[tokio::test]
+    async fn peers_with_another_key_are_rejected() {
+        let (dialed, accepted) = connect(Some(random_key()), Some(random_key())).await;
+
+        assert!(matches!(dialed, Err(HandshakeError::PskMismatch)));
+        assert!(matches!(accepted, Err(HandshakeError::PskMismatch)));
+    }
+
+    #[tokio::test]
+    async fn peers_without_a_key_are_rejected() {
+        let (_, accepted) = connect(None, Some(random_key())).await;
+
+        assert!(matches!(accepted, Err(HandshakeError::PskMismatch)));
+    }
+
+    #[test]
+    fn reads_swarm_key_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("swarm.key");
+        fs::write(&path, SWARM_KEY).unwrap();
+        let key = read_swarm_key(&path);
+        fs::write(&path, "/key/swarm/psk/1.0.0/\n/base16/\nnot hex\n").unwrap();
+        let invalid = read_swarm_key(&path);
+
+        assert_eq!(key.unwrap(), SWARM_KEY.parse().unwrap());
+        assert_eq!(invalid.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,256 @@
+//! Choice of the authentication protocol, secio or Noise.
+//!
+//! secio is deprecated and no longer spoken by other libp2p
+//! implementations. Offering both lets a fleet move from secio to Noise
+//! without a flag day: first every peer offers both, then peers switch to
+//! Noise only.
+
+use std::{error::Error, fmt, io, str::FromStr};
+
+use futures::prelude::*;
+use libp2p::{
+    core::{
+        either::{EitherError, EitherOutput},
+        identity,
+        upgrade::{self, NegotiationError, OptionalUpgrade, ProtocolError, SelectUpgrade, Version},
+        ConnectedPoint, Negotiated, UpgradeError,
+    },
+    noise::{self, NoiseAuthenticated, NoiseConfig, NoiseError, NoiseOutput, X25519Spec, XX},
+    pnet::{PnetError, PreSharedKey},
+    secio::{SecioConfig, SecioError, SecioOutput},
+    PeerId,
+};
+
+use crate::psk::{self, PskStream};
+
+/// Authentication protocols offered, both when dialing and listening.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Security {
+    /// secio only.
+    #[default]
+    Secio,
+    /// Noise XX only.
+    Noise,
+    /// Noise XX and secio, Noise is preferred.
+    Both,
+}
+
+impl Security {
+    /// Whether Noise is offered.
+    pub fn noise(self) -> bool {
+        self != Security::Secio
+    }
+
+    /// Whether secio is offered.
+    pub fn secio(self) -> bool {
+        self != Security::Noise
+    }
+
+    /// The upgrade authenticating a connection as `keypair` with the
+    /// offered protocols.
+    pub fn upgrade(self, keypair: identity::Keypair) -> Result<SecurityUpgrade, NoiseError> {
+        let noise = if self.noise() {
+            let dh_keys = noise::Keypair::<X25519Spec>::new().into_authentic(&keypair)?;
+            OptionalUpgrade::some(NoiseConfig::xx(dh_keys).into_authenticated())
+        } else {
+            OptionalUpgrade::none()
+        };
+        let secio = if self.secio() {
+            OptionalUpgrade::some(SecioConfig::new(keypair))
+        } else {
+            OptionalUpgrade::none()
+        };
+        Ok(SelectUpgrade::new(noise, secio))
+    }
+}
+
+impl FromStr for Security {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "secio" => Ok(Security::Secio),
+            "noise" => Ok(Security::Noise),
+            "both" => Ok(Security::Both),
+            _ => {
+                let msg = format!(
+                    "invalid security protocol (want secio, noise or both): {}",
+                    s
+                );
+                Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+            }
+        }
+    }
+}
+
+/// Upgrade negotiating one of the offered authentication protocols.
+pub type SecurityUpgrade = SelectUpgrade<
+    OptionalUpgrade<NoiseAuthenticated<XX, X25519Spec, ()>>,
+    OptionalUpgrade<SecioConfig>,
+>;
+
+/// Error authenticating a connection.
+pub type SecurityError = EitherError<NoiseError, SecioError>;
+
+/// Connection, possibly in a private network, handed to the security upgrade.
+pub type PskOutput<C> = EitherOutput<PskStream<C>, C>;
+
+/// Connection authenticated by [`secure`].
+pub type Secured<C> =
+    EitherOutput<NoiseOutput<Negotiated<PskOutput<C>>>, SecioOutput<Negotiated<PskOutput<C>>>>;
+
+/// Error securing a connection with [`secure`].
+#[derive(Debug)]
+pub enum HandshakeError {
+    /// The private network handshake failed.
+    Pnet(PnetError),
+    /// The remote does not hold our swarm key.
+    PskMismatch,
+    /// Negotiating or running the authentication protocol failed.
+    Upgrade(UpgradeError<SecurityError>),
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::Pnet(e) => write!(f, "private network handshake failed: {}", e),
+            HandshakeError::PskMismatch => psk::PskMismatch.fmt(f),
+            HandshakeError::Upgrade(e) => write!(f, "authentication failed: {}", e),
+        }
+    }
+}
+
+impl Error for HandshakeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            HandshakeError::Pnet(e) => Some(e),
+            HandshakeError::PskMismatch => None,
+            HandshakeError::Upgrade(e) => Some(e),
+        }
+    }
+}
+
+impl From<UpgradeError<SecurityError>> for HandshakeError {
+    fn from(e: UpgradeError<SecurityError>) -> Self {
+        // A wrong swarm key shows up as the first read of the negotiation failing.
+        match &e {
+            UpgradeError::Select(NegotiationError::ProtocolError(ProtocolError::IoError(io)))
+                if psk::is_psk_mismatch(io) =>
+            {
+                HandshakeError::PskMismatch
+            }
+            _ => HandshakeError::Upgrade(e),
+        }
+    }
+}
+
+/// Secures `conn`: runs the private network handshake if we have a swarm
+/// key, then authenticates the remote with `upgrade`.
+pub async fn secure<C>(
+    conn: C,
+    endpoint: ConnectedPoint,
+    psk: Option<PreSharedKey>,
+    upgrade: SecurityUpgrade,
+) -> Result<(PeerId, Secured<C>), HandshakeError>
+where
+    C: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let conn = match psk {
+        Some(psk) => EitherOutput::First(
+            psk::handshake(psk, conn)
+                .await
+                .map_err(HandshakeError::Pnet)?,
+        ),
+        None => EitherOutput::Second(conn),
+    };
+    let output = upgrade::apply(conn, upgrade, endpoint, Version::V1).await?;
+    Ok(authenticated(output))
+}
+
+/// Moves the authenticated `PeerId` out of the negotiated protocol's
+/// output.
+pub fn authenticated<A, B>(
+    output: EitherOutput<(PeerId, A), (PeerId, B)>,
+) -> (PeerId, EitherOutput<A, B>) {
+    match output {
+        EitherOutput::First((peer, conn)) => (peer, EitherOutput::First(conn)),
+        EitherOutput::Second((peer, conn)) => (peer, EitherOutput::Second(conn)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use libp2p::{
+        core::{
+            transport::MemoryTransport,
+            upgrade::{self, Version},
+        },
+        Transport,
+    };
+
+    /// Dials a listener over an in-memory transport, each authenticating
+    /// with the given protocols, and returns whether they agreed on Noise.
+    async fn handshake(dialer: Security, listener: Security) -> Option<bool> {
+        let dialer_keys = identity::Keypair::generate_ed25519();
+        let listener_keys = identity::Keypair::generate_ed25519();
+        let dialer_id = PeerId::from(dialer_keys.public());
+        let listener_id = PeerId::from(listener_keys.public());
+
+        let transport = |security: Security, keys| {
+            let upgrade = security.upgrade(keys).unwrap();
+            MemoryTransport.and_then(move |conn, endpoint| {
+                upgrade::apply(conn, upgrade, endpoint, Version::V1)
+            })
+        };
+        let dialer = transport(dialer, dialer_keys);
+        let listener = transport(listener, listener_keys);
+
+        match testing::connect_memory(dialer, listener).await {
+            (Ok(dialed), Ok(accepted)) => {
+                let (seen_by_dialer, conn) = authenticated(dialed);
+                let (seen_by_listener, _) = authenticated(accepted);
+                assert_eq!(seen_by_dialer, listener_id);
+                assert_eq!(seen_by_listener, dialer_id);
+                Some(matches!(conn, EitherOutput::First(_)))
+            }
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn peers_interoperate_when_they_share_a_protocol() {
+        use Security::*;
+
+        for (dialer, listener, noise) in &[
+            (Secio, Secio, false),
+            (Noise, Noise, true),
+            (Both, Both, true),
+            (Both, Secio, false),
+            (Secio, Both, false),
+            (Both, Noise, true),
+            (Noise, Both, true),
+        ] {
+            assert_eq!(
+                handshake(*dialer, *listener).await,
+                Some(*noise),
+                "{:?} dialer and {:?} listener",
+                dialer,
+                listener
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn peers_without_a_shared_protocol_fail() {
+        assert!(handshake(Security::Secio, Security::Noise).await.is_none());
+        assert!(handshake(Security::Noise, Security::Secio).await.is_none());
+    }
+
+    #[test]
+    fn parses_security() {
+        assert_eq!("both".parse::<Security>().unwrap(), Security::Both);
+        assert!("tls".parse::<Security>().is_err());
+    }
+}
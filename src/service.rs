@@ -0,0 +1,156 @@
+//! Ephemeral onion services for the listener.
+
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use anyhow::{Context, Result};
+use libp2p::{identity, Multiaddr};
+use log::info;
+
+use crate::{
+    client_auth::ClientAuthPublicKey,
+    control::{AddOnion, ControlConfig, ControlConnection, ControlError, OnionKey, OnionService},
+    keys,
+};
+
+/// How long we wait for the first descriptor upload by default.
+pub const DEFAULT_PUBLISH_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// Configuration of an onion service created via the Tor control port.
+#[derive(Debug, Clone)]
+pub struct EphemeralOnion {
+    /// How to reach Tor's control port.
+    control: ControlConfig,
+    /// The onion service's virtual port.
+    virtual_port: u16,
+    /// Where the service's key comes from.
+    key: KeySource,
+    /// How long to wait for the service's descriptor to be published.
+    publish_timeout: Duration,
+    /// Clients allowed to connect, anyone may if empty.
+    authorized_clients: Vec<ClientAuthPublicKey>,
+}
+
+/// Where an ephemeral onion service's key comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeySource {
+    /// A new key on every start, the onion address changes each time.
+    New,
+    /// The given key.
+    Fixed(OnionKey),
+    /// Loaded from the file, or generated and saved there if the file does
+    /// not exist, so that the onion address stays the same across restarts.
+    File(PathBuf),
+    /// The swarm's ed25519 identity, binding the onion address to our
+    /// `PeerId`.
+    Identity,
+}
+
+impl EphemeralOnion {
+    /// Creates the configuration for a service on `virtual_port` with a
+    /// new key on every start.
+    pub fn new(control: ControlConfig, virtual_port: u16) -> Self {
+        EphemeralOnion {
+            control,
+            virtual_port,
+            key: KeySource::New,
+            publish_timeout: DEFAULT_PUBLISH_TIMEOUT,
+            authorized_clients: Vec::new(),
+        }
+    }
+
+    /// Sets where the service's key comes from, `KeySource::New` by default.
+    pub fn key_source(mut self, key: KeySource) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Sets how long to wait for the service's descriptor to be published.
+    pub fn publish_timeout(mut self, timeout: Duration) -> Self {
+        self.publish_timeout = timeout;
+        self
+    }
+
+    /// Only lets the client with the private key for `key` connect, may be
+    /// given several times.
+    pub fn authorized_client(mut self, key: ClientAuthPublicKey) -> Self {
+        self.authorized_clients.push(key);
+        self
+    }
+
+    /// Creates the onion service, forwarding connections to `local`.
+    /// `identity` is the swarm's identity, used if configured as the key.
+    ///
+    /// Returns once Tor has uploaded the service's descriptor to at least
+    /// one HSDir, before that peers can't reach the service.
+    pub async fn create(
+        &self,
+        local: SocketAddr,
+        identity: &identity::Keypair,
+    ) -> Result<RunningOnion> {
+        let (key, save) = match &self.key {
+            KeySource::New => (OnionKey::New, None),
+            KeySource::Fixed(key) => (key.clone(), None),
+            KeySource::File(path) => match keys::read_onion_key(path)
+                .with_context(|| format!("failed to read onion key: {}", path.display()))?
+            {
+                Some(key) => (key, None),
+                None => (OnionKey::New, Some(path)),
+            },
+            KeySource::Identity => {
+                let key = OnionKey::from_identity(identity)
+                    .context("only ed25519 identities can be onion service keys")?;
+                (key, None)
+            }
+        };
+
+        let mut control = self.control.connect().await?;
+        // Subscribe before creating the service so we can't miss the upload.
+        control.set_events(&["HS_DESC"]).await?;
+        let request = self.authorized_clients.iter().fold(
+            AddOnion::new(key)
+                .discard_key(save.is_none())
+                .port(self.virtual_port, local),
+            |request, key| request.authorized_client(*key),
+        );
+        let service = control.add_onion(&request).await?;
+
+        if let Some(path) = save {
+            let key = service
+                .private_key
+                .as_ref()
+                .context("Tor did not return the onion service key")?;
+            keys::write_onion_key(path, key)
+                .with_context(|| format!("failed to save onion key: {}", path.display()))?;
+            info!("saved onion service key to {}", path.display());
+        }
+
+        let uploaded = control
+            .wait_for_upload(&service.service_id, self.publish_timeout)
+            .await?;
+        info!("onion service descriptor uploaded to {}", uploaded.hsdir);
+        control.set_events(&[]).await?;
+
+        let address = service.multiaddr(self.virtual_port)?;
+        Ok(RunningOnion {
+            control,
+            service,
+            address,
+        })
+    }
+}
+
+/// An onion service that exists as long as this is not dropped.
+#[derive(Debug)]
+pub struct RunningOnion {
+    control: ControlConnection,
+    service: OnionService,
+    /// The service's `/onion3` address.
+    pub address: Multiaddr,
+}
+
+impl RunningOnion {
+    /// Removes the onion service.
+    pub async fn remove(mut self) -> Result<(), ControlError> {
+        self.control.del_onion(&self.service.service_id).await
+    }
+}
@@ -0,0 +1,769 @@
+//! Minimal SOCKS5 client used to reach the Tor proxy.
+//!
+//! The proxy is reached at a [`LocalEndpoint`], either a TCP or a
+//! Unix-domain socket. We do not use `tokio-socks`, it only speaks SOCKS
+//! over TCP and folds Tor's extended reply codes into a single error.
+
+use std::{
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    process,
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::endpoint::{LocalEndpoint, LocalStream};
+
+/// Default port for the Tor SOCKS5 proxy.
+pub const DEFAULT_SOCKS_PORT: u16 = 9050;
+
+const SOCKS_VERSION: u8 = 0x05;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const METHOD_NO_ACCEPTABLE: u8 = 0xff;
+const AUTH_VERSION: u8 = 0x01;
+const AUTH_SUCCEEDED: u8 = 0x00;
+const CMD_CONNECT: u8 = 0x01;
+/// Tor extension: resolve a host name, see Tor's socks-extensions.txt.
+const CMD_RESOLVE: u8 = 0xf0;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+const REPLY_SUCCEEDED: u8 = 0x00;
+
+/// Opens a connection to the Tor SOCKS5 proxy at `endpoint`.
+pub async fn connect_proxy(endpoint: &LocalEndpoint) -> Result<LocalStream, SocksError> {
+    endpoint
+        .connect()
+        .await
+        .map_err(SocksError::ProxyUnreachable)
+}
+
+/// Username used for all SOCKS5 isolation credentials.
+const ISOLATION_USERNAME: &str = "ping-pong";
+
+/// Counter used to generate unique credentials for `IsolationPolicy::PerDial`.
+static DIAL_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Controls which connections Tor may put on the same circuit.
+///
+/// Tor's `IsolateSOCKSAuth` (on by default) never shares a circuit between
+/// SOCKS5 connections that authenticated with different credentials, we use
+/// this to keep connections to different peers apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IsolationPolicy {
+    /// All connections use an anonymous SOCKS5 session and may share circuits.
+    Shared,
+    /// Connections to the same peer may share a circuit. The peer is the
+    /// `/p2p` `PeerId` of the dialed address if it has one, otherwise the
+    /// host, ignoring the port.
+    #[default]
+    PerPeer,
+    /// Every connection gets its own circuit.
+    PerDial,
+}
+
+impl IsolationPolicy {
+    /// SOCKS5 credentials to use when connecting to `peer`.
+    pub fn credentials(self, peer: &str) -> Option<Credentials> {
+        let password = match self {
+            IsolationPolicy::Shared => return None,
+            IsolationPolicy::PerPeer => format!("peer:{}", peer),
+            IsolationPolicy::PerDial => {
+                let n = DIAL_COUNTER.fetch_add(1, Ordering::Relaxed);
+                format!("dial:{}:{}", process::id(), n)
+            }
+        };
+        Some(Credentials::new(ISOLATION_USERNAME, password))
+    }
+}
+
+impl fmt::Display for IsolationPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            IsolationPolicy::Shared => "shared",
+            IsolationPolicy::PerPeer => "per-peer",
+            IsolationPolicy::PerDial => "per-dial",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl FromStr for IsolationPolicy {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "shared" => Ok(IsolationPolicy::Shared),
+            "per-peer" => Ok(IsolationPolicy::PerPeer),
+            "per-dial" => Ok(IsolationPolicy::PerDial),
+            _ => {
+                let msg = format!(
+                    "invalid isolation policy (want shared, per-peer or per-dial): {}",
+                    s
+                );
+                Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+            }
+        }
+    }
+}
+
+/// Destination of a SOCKS5 request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TargetAddr {
+    /// An IP address and port.
+    Ip(SocketAddr),
+    /// A host name and port, resolved by the proxy.
+    Domain(String, u16),
+}
+
+impl TargetAddr {
+    /// The host part, without the port.
+    pub fn host(&self) -> String {
+        match self {
+            TargetAddr::Ip(addr) => addr.ip().to_string(),
+            TargetAddr::Domain(host, _) => host.clone(),
+        }
+    }
+
+    /// True if this is an onion service address.
+    pub fn is_onion(&self) -> bool {
+        match self {
+            TargetAddr::Ip(_) => false,
+            TargetAddr::Domain(host, _) => host.ends_with(".onion"),
+        }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<(), SocksError> {
+        let port = match self {
+            TargetAddr::Ip(SocketAddr::V4(addr)) => {
+                buf.push(ATYP_IPV4);
+                buf.extend_from_slice(&addr.ip().octets());
+                addr.port()
+            }
+            TargetAddr::Ip(SocketAddr::V6(addr)) => {
+                buf.push(ATYP_IPV6);
+                buf.extend_from_slice(&addr.ip().octets());
+                addr.port()
+            }
+            TargetAddr::Domain(host, port) => {
+                if host.is_empty() || host.len() > 255 {
+                    return Err(SocksError::InvalidTarget(
+                        "host name must be between 1 and 255 bytes",
+                    ));
+                }
+                buf.push(ATYP_DOMAIN);
+                buf.push(host.len() as u8);
+                buf.extend_from_slice(host.as_bytes());
+                *port
+            }
+        };
+        buf.extend_from_slice(&port.to_be_bytes());
+        Ok(())
+    }
+}
+
+impl fmt::Display for TargetAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TargetAddr::Ip(addr) => write!(f, "{}", addr),
+            TargetAddr::Domain(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+/// Parses `ip:port` or `host:port`, host names are left for the proxy to resolve.
+impl FromStr for TargetAddr {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse() {
+            return Ok(TargetAddr::Ip(addr));
+        }
+        let invalid = || {
+            let msg = format!("invalid SOCKS target (want host:port): {}", s);
+            io::Error::new(io::ErrorKind::InvalidInput, msg)
+        };
+        let (host, port) = s.rsplit_once(':').ok_or_else(invalid)?;
+        let port = port.parse().map_err(|_| invalid())?;
+        Ok(TargetAddr::Domain(host.to_string(), port))
+    }
+}
+
+/// SOCKS5 username/password credentials (RFC 1929).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    username: String,
+    password: String,
+}
+
+impl Credentials {
+    /// Creates new credentials, both fields are truncated to 255 bytes on the wire.
+    pub fn new(username: impl Into<String>, password: impl Into<String>) -> Self {
+        Credentials {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+
+    /// The username, truncated to 255 bytes.
+    pub fn username(&self) -> &str {
+        truncate(&self.username)
+    }
+
+    /// The password, truncated to 255 bytes.
+    pub fn password(&self) -> &str {
+        truncate(&self.password)
+    }
+
+    fn request(&self) -> Vec<u8> {
+        let username = self.username().as_bytes();
+        let password = self.password().as_bytes();
+
+        let mut req = vec![AUTH_VERSION, username.len() as u8];
+        req.extend_from_slice(username);
+        req.push(password.len() as u8);
+        req.extend_from_slice(password);
+        req
+    }
+}
+
+fn truncate(s: &str) -> &str {
+    let mut end = s.len().min(255);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// Errors returned when connecting through the Tor SOCKS5 proxy.
+#[derive(Debug)]
+pub enum SocksError {
+    /// Could not connect to the proxy, most likely Tor is not running.
+    ProxyUnreachable(io::Error),
+    /// I/O error while talking to the proxy.
+    Io(io::Error),
+    /// The proxy sent a malformed response.
+    Protocol(&'static str),
+    /// The proxy does not accept any of the offered authentication methods.
+    NoAcceptableAuthMethods,
+    /// The proxy rejected our username/password, contains the status code.
+    AuthRejected(u8),
+    /// The target address can not be encoded in a SOCKS5 request.
+    InvalidTarget(&'static str),
+    /// The proxy could not establish the connection.
+    Reply(ReplyCode),
+}
+
+impl SocksError {
+    /// The SOCKS5 reply code, if the proxy refused the request.
+    pub fn reply_code(&self) -> Option<ReplyCode> {
+        match self {
+            SocksError::Reply(code) => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// The closest matching `io::ErrorKind`.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            SocksError::ProxyUnreachable(e) | SocksError::Io(e) => e.kind(),
+            SocksError::Protocol(_) => io::ErrorKind::InvalidData,
+            SocksError::NoAcceptableAuthMethods | SocksError::AuthRejected(_) => {
+                io::ErrorKind::PermissionDenied
+            }
+            SocksError::InvalidTarget(_) => io::ErrorKind::InvalidInput,
+            SocksError::Reply(code) => code.kind(),
+        }
+    }
+}
+
+impl fmt::Display for SocksError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SocksError::ProxyUnreachable(e) => write!(f, "Tor SOCKS5 proxy unreachable: {}", e),
+            SocksError::Io(e) => write!(f, "I/O error talking to Tor SOCKS5 proxy: {}", e),
+            SocksError::Protocol(msg) => write!(f, "SOCKS5 protocol error: {}", msg),
+            SocksError::NoAcceptableAuthMethods => write!(f, "no acceptable auth methods"),
+            SocksError::AuthRejected(status) => {
+                write!(f, "proxy rejected credentials, status {:#04x}", status)
+            }
+            SocksError::InvalidTarget(msg) => write!(f, "invalid target address: {}", msg),
+            SocksError::Reply(code) => write!(f, "{} ({:#04x})", code, code.code()),
+        }
+    }
+}
+
+impl std::error::Error for SocksError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SocksError::ProxyUnreachable(e) | SocksError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SocksError {
+    fn from(e: io::Error) -> Self {
+        SocksError::Io(e)
+    }
+}
+
+impl From<SocksError> for io::Error {
+    fn from(e: SocksError) -> Self {
+        io::Error::new(e.kind(), e)
+    }
+}
+
+/// SOCKS5 reply codes, including Tor's extended onion service errors.
+///
+/// Tor only sends the extended codes (0xF0-0xF7) if the SOCKS port is
+/// configured with the `ExtendedErrors` flag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplyCode {
+    /// 0x01: General SOCKS server failure.
+    GeneralFailure,
+    /// 0x02: Connection not allowed by ruleset.
+    NotAllowed,
+    /// 0x03: Network unreachable.
+    NetworkUnreachable,
+    /// 0x04: Host unreachable.
+    HostUnreachable,
+    /// 0x05: Connection refused.
+    ConnectionRefused,
+    /// 0x06: TTL expired.
+    TtlExpired,
+    /// 0x07: Command not supported.
+    CommandNotSupported,
+    /// 0x08: Address type not supported.
+    AddressTypeNotSupported,
+    /// 0xF0: Onion service descriptor can not be found.
+    OnionDescriptorNotFound,
+    /// 0xF1: Onion service descriptor is invalid.
+    OnionDescriptorInvalid,
+    /// 0xF2: Onion service introduction failed.
+    OnionIntroFailed,
+    /// 0xF3: Onion service rendezvous failed.
+    OnionRendezvousFailed,
+    /// 0xF4: Onion service missing client authorization.
+    OnionMissingClientAuth,
+    /// 0xF5: Onion service wrong client authorization.
+    OnionWrongClientAuth,
+    /// 0xF6: Onion service invalid address.
+    OnionBadAddress,
+    /// 0xF7: Onion service introduction timed out.
+    OnionIntroTimedOut,
+    /// Any other reply code.
+    Unknown(u8),
+}
+
+impl ReplyCode {
+    /// Decodes a (non-zero) reply code.
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0x01 => ReplyCode::GeneralFailure,
+            0x02 => ReplyCode::NotAllowed,
+            0x03 => ReplyCode::NetworkUnreachable,
+            0x04 => ReplyCode::HostUnreachable,
+            0x05 => ReplyCode::ConnectionRefused,
+            0x06 => ReplyCode::TtlExpired,
+            0x07 => ReplyCode::CommandNotSupported,
+            0x08 => ReplyCode::AddressTypeNotSupported,
+            0xf0 => ReplyCode::OnionDescriptorNotFound,
+            0xf1 => ReplyCode::OnionDescriptorInvalid,
+            0xf2 => ReplyCode::OnionIntroFailed,
+            0xf3 => ReplyCode::OnionRendezvousFailed,
+            0xf4 => ReplyCode::OnionMissingClientAuth,
+            0xf5 => ReplyCode::OnionWrongClientAuth,
+            0xf6 => ReplyCode::OnionBadAddress,
+            0xf7 => ReplyCode::OnionIntroTimedOut,
+            other => ReplyCode::Unknown(other),
+        }
+    }
+
+    /// The on-the-wire reply code.
+    pub fn code(self) -> u8 {
+        match self {
+            ReplyCode::GeneralFailure => 0x01,
+            ReplyCode::NotAllowed => 0x02,
+            ReplyCode::NetworkUnreachable => 0x03,
+            ReplyCode::HostUnreachable => 0x04,
+            ReplyCode::ConnectionRefused => 0x05,
+            ReplyCode::TtlExpired => 0x06,
+            ReplyCode::CommandNotSupported => 0x07,
+            ReplyCode::AddressTypeNotSupported => 0x08,
+            ReplyCode::OnionDescriptorNotFound => 0xf0,
+            ReplyCode::OnionDescriptorInvalid => 0xf1,
+            ReplyCode::OnionIntroFailed => 0xf2,
+            ReplyCode::OnionRendezvousFailed => 0xf3,
+            ReplyCode::OnionMissingClientAuth => 0xf4,
+            ReplyCode::OnionWrongClientAuth => 0xf5,
+            ReplyCode::OnionBadAddress => 0xf6,
+            ReplyCode::OnionIntroTimedOut => 0xf7,
+            ReplyCode::Unknown(code) => code,
+        }
+    }
+
+    /// True if this is one of Tor's extended onion service errors.
+    pub fn is_onion_service_error(self) -> bool {
+        (0xf0..=0xf7).contains(&self.code())
+    }
+
+    fn kind(self) -> io::ErrorKind {
+        match self {
+            ReplyCode::ConnectionRefused | ReplyCode::NotAllowed => {
+                io::ErrorKind::ConnectionRefused
+            }
+            ReplyCode::TtlExpired | ReplyCode::OnionIntroTimedOut => io::ErrorKind::TimedOut,
+            ReplyCode::OnionBadAddress | ReplyCode::AddressTypeNotSupported => {
+                io::ErrorKind::InvalidInput
+            }
+            ReplyCode::OnionMissingClientAuth | ReplyCode::OnionWrongClientAuth => {
+                io::ErrorKind::PermissionDenied
+            }
+            _ => io::ErrorKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for ReplyCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ReplyCode::GeneralFailure => "general SOCKS server failure",
+            ReplyCode::NotAllowed => "connection not allowed by ruleset",
+            ReplyCode::NetworkUnreachable => "network unreachable",
+            ReplyCode::HostUnreachable => "host unreachable",
+            ReplyCode::ConnectionRefused => "connection refused",
+            ReplyCode::TtlExpired => "TTL expired",
+            ReplyCode::CommandNotSupported => "command not supported",
+            ReplyCode::AddressTypeNotSupported => "address type not supported",
+            ReplyCode::OnionDescriptorNotFound => "onion service descriptor can not be found",
+            ReplyCode::OnionDescriptorInvalid => "onion service descriptor is invalid",
+            ReplyCode::OnionIntroFailed => "onion service introduction failed",
+            ReplyCode::OnionRendezvousFailed => "onion service rendezvous failed",
+            ReplyCode::OnionMissingClientAuth => "onion service missing client authorization",
+            ReplyCode::OnionWrongClientAuth => "onion service wrong client authorization",
+            ReplyCode::OnionBadAddress => "onion service invalid address",
+            ReplyCode::OnionIntroTimedOut => "onion service introduction timed out",
+            ReplyCode::Unknown(_) => "unknown SOCKS5 reply",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Finds the `SocksError` that caused `err`, if any.
+///
+/// Walks the error chain, looking inside any `io::Error` wrappers on the way.
+pub fn find_socks_error<'a>(err: &'a (dyn std::error::Error + 'static)) -> Option<&'a SocksError> {
+    if let Some(socks) = err.downcast_ref::<SocksError>() {
+        return Some(socks);
+    }
+    if let Some(inner) = err.downcast_ref::<io::Error>().and_then(|e| e.get_ref()) {
+        if let Some(socks) = find_socks_error(inner) {
+            return Some(socks);
+        }
+    }
+    err.source().and_then(find_socks_error)
+}
+
+/// Performs a SOCKS5 CONNECT to `dest` over `stream`.
+///
+/// Domain names are passed to the proxy as is so that Tor does the resolving.
+/// If `auth` is given we only offer username/password authentication.
+pub async fn connect<S>(
+    stream: &mut S,
+    dest: &TargetAddr,
+    auth: Option<&Credentials>,
+) -> Result<(), SocksError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    request(stream, CMD_CONNECT, dest, auth).await?;
+    Ok(())
+}
+
+/// Resolves `host` to an IP address using Tor's SOCKS5 RESOLVE extension.
+///
+/// The lookup is done by a Tor exit relay so no DNS query leaves this machine.
+pub async fn resolve<S>(
+    stream: &mut S,
+    host: &str,
+    auth: Option<&Credentials>,
+) -> Result<IpAddr, SocksError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let dest = TargetAddr::Domain(host.to_string(), 0);
+    request(stream, CMD_RESOLVE, &dest, auth)
+        .await?
+        .ok_or(SocksError::Protocol(
+            "RESOLVE reply did not contain an IP address",
+        ))
+}
+
+// Runs a single SOCKS5 request, returns the bound IP address from the reply, if any.
+async fn request<S>(
+    stream: &mut S,
+    cmd: u8,
+    dest: &TargetAddr,
+    auth: Option<&Credentials>,
+) -> Result<Option<IpAddr>, SocksError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let method = match auth {
+        Some(_) => METHOD_USERNAME_PASSWORD,
+        None => METHOD_NO_AUTH,
+    };
+    stream.write_all(&[SOCKS_VERSION, 1, method]).await?;
+
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    check_version(reply[0])?;
+    match (reply[1], auth) {
+        (METHOD_NO_AUTH, None) => {}
+        (METHOD_USERNAME_PASSWORD, Some(creds)) => authenticate(stream, creds).await?,
+        (METHOD_NO_ACCEPTABLE, _) => return Err(SocksError::NoAcceptableAuthMethods),
+        _ => return Err(SocksError::Protocol("proxy selected unknown auth method")),
+    }
+
+    stream.write_all(&request_bytes(cmd, dest)?).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    check_version(reply[0])?;
+    if reply[1] != REPLY_SUCCEEDED {
+        return Err(SocksError::Reply(ReplyCode::from_code(reply[1])));
+    }
+
+    let bound = match reply[3] {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            stream.read_exact(&mut octets).await?;
+            Some(IpAddr::V4(Ipv4Addr::from(octets)))
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            stream.read_exact(&mut octets).await?;
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            let mut name = vec![0u8; len[0] as usize];
+            stream.read_exact(&mut name).await?;
+            None
+        }
+        _ => return Err(SocksError::Protocol("unknown address type in reply")),
+    };
+    let mut port = [0u8; 2];
+    stream.read_exact(&mut port).await?;
+
+    Ok(bound)
+}
+
+async fn authenticate<S>(stream: &mut S, creds: &Credentials) -> Result<(), SocksError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(&creds.request()).await?;
+
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != AUTH_VERSION {
+        return Err(SocksError::Protocol("invalid auth response version"));
+    }
+    if reply[1] != AUTH_SUCCEEDED {
+        return Err(SocksError::AuthRejected(reply[1]));
+    }
+
+    Ok(())
+}
+
+fn request_bytes(cmd: u8, dest: &TargetAddr) -> Result<Vec<u8>, SocksError> {
+    let mut req = vec![SOCKS_VERSION, cmd, 0x00];
+    dest.encode(&mut req)?;
+    Ok(req)
+}
+
+fn check_version(version: u8) -> Result<(), SocksError> {
+    if version != SOCKS_VERSION {
+        return Err(SocksError::Protocol("invalid response version"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connect_request_uses_domain_name() {
+        let dest = TargetAddr::Domain("abc.onion".into(), 7);
+        let got = request_bytes(CMD_CONNECT, &dest).expect("failed to build request");
+        let mut want = vec![5, 1, 0, 3, 9];
+        want.extend_from_slice(b"abc.onion");
+        want.extend_from_slice(&[0, 7]);
+
+        assert_eq!(got, want);
+    }
+
+    #[tokio::test]
+    async fn can_connect_via_unix_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("socks");
+        let mut listener = tokio::net::UnixListener::bind(&socket).expect("failed to bind");
+
+        let proxy = tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.expect("failed to accept");
+            let mut greeting = [0u8; 3];
+            sock.read_exact(&mut greeting).await.unwrap();
+            sock.write_all(&[5, METHOD_NO_AUTH]).await.unwrap();
+
+            let mut request = vec![0u8; 5 + 9 + 2];
+            sock.read_exact(&mut request).await.unwrap();
+            sock.write_all(&[5, 0, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            request
+        });
+
+        let dest = TargetAddr::Domain("abc.onion".into(), 7);
+        let endpoint = LocalEndpoint::Unix(socket);
+        let mut stream = endpoint
+            .connect()
+            .await
+            .expect("failed to connect to proxy");
+        connect(&mut stream, &dest, None)
+            .await
+            .expect("SOCKS5 handshake failed");
+
+        let request = proxy.await.unwrap();
+        assert_eq!(request, request_bytes(CMD_CONNECT, &dest).unwrap());
+    }
+
+    #[test]
+    fn can_parse_target_addr() {
+        let want = TargetAddr::Domain("abc.onion".into(), 7);
+        assert_eq!("abc.onion:7".parse::<TargetAddr>().unwrap(), want);
+
+        let want = TargetAddr::Ip("[::1]:7".parse().unwrap());
+        assert_eq!("[::1]:7".parse::<TargetAddr>().unwrap(), want);
+
+        assert!("abc.onion".parse::<TargetAddr>().is_err());
+    }
+
+    #[tokio::test]
+    async fn reports_onion_service_errors() {
+        let any: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let mut listener = tokio::net::TcpListener::bind(&any).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            sock.read_exact(&mut greeting).await.unwrap();
+            sock.write_all(&[5, METHOD_NO_AUTH]).await.unwrap();
+
+            let mut request = vec![0u8; 5 + 9 + 2];
+            sock.read_exact(&mut request).await.unwrap();
+            sock.write_all(&[5, 0xf0, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+        });
+
+        let dest = TargetAddr::Domain("abc.onion".into(), 7);
+        let mut stream = connect_proxy(&LocalEndpoint::Tcp(addr)).await.unwrap();
+        let err = connect(&mut stream, &dest, None)
+            .await
+            .expect_err("connect should fail");
+
+        let code = err.reply_code().expect("no reply code");
+        assert_eq!(code, ReplyCode::OnionDescriptorNotFound);
+        assert!(code.is_onion_service_error());
+    }
+
+    #[tokio::test]
+    async fn can_resolve_via_tor() {
+        let any: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let mut listener = tokio::net::TcpListener::bind(&any).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let proxy = tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            sock.read_exact(&mut greeting).await.unwrap();
+            sock.write_all(&[5, METHOD_NO_AUTH]).await.unwrap();
+
+            let mut request = vec![0u8; 5 + 11 + 2];
+            sock.read_exact(&mut request).await.unwrap();
+            sock.write_all(&[5, 0, 0, ATYP_IPV4, 93, 184, 216, 34, 0, 0])
+                .await
+                .unwrap();
+            request
+        });
+
+        let mut stream = connect_proxy(&LocalEndpoint::Tcp(addr)).await.unwrap();
+        let got = resolve(&mut stream, "example.com", None)
+            .await
+            .expect("resolve failed");
+
+        assert_eq!(got, IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34)));
+        assert_eq!(proxy.await.unwrap()[1], CMD_RESOLVE);
+    }
+
+    #[test]
+    fn can_encode_ip_target() {
+        let dest = TargetAddr::Ip("10.0.0.1:4001".parse().unwrap());
+        let got = request_bytes(CMD_CONNECT, &dest).expect("failed to build request");
+
+        assert_eq!(got, vec![5, 1, 0, 1, 10, 0, 0, 1, 0x0f, 0xa1]);
+    }
+
+    #[test]
+    fn can_find_socks_error_in_chain() {
+        let err: io::Error = SocksError::Reply(ReplyCode::OnionIntroFailed).into();
+        let wrapped = io::Error::other(err);
+
+        let got = find_socks_error(&wrapped).and_then(SocksError::reply_code);
+        assert_eq!(got, Some(ReplyCode::OnionIntroFailed));
+    }
+
+    #[test]
+    fn reply_codes_round_trip() {
+        for code in 1..=255u8 {
+            assert_eq!(ReplyCode::from_code(code).code(), code);
+        }
+    }
+
+    #[test]
+    fn per_peer_credentials_are_stable() {
+        let policy = IsolationPolicy::PerPeer;
+        let a = policy.credentials("a.onion");
+        let b = policy.credentials("b.onion");
+
+        assert_eq!(a, policy.credentials("a.onion"));
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn per_dial_credentials_are_unique() {
+        let policy = IsolationPolicy::PerDial;
+
+        assert_ne!(policy.credentials("a.onion"), policy.credentials("a.onion"));
+    }
+
+    #[test]
+    fn shared_policy_has_no_credentials() {
+        assert_eq!(IsolationPolicy::Shared.credentials("a.onion"), None);
+    }
+
+    #[test]
+    fn can_encode_credentials() {
+        let creds = Credentials::new("ab", "xyz");
+
+        assert_eq!(creds.request(), vec![1, 2, b'a', b'b', 3, b'x', b'y', b'z']);
+    }
+}
@@ -0,0 +1,56 @@
+//! Helpers shared by the tests of the transport layers.
+
+use futures::{future, prelude::*};
+use libp2p::{
+    core::transport::{ListenerEvent, Transport},
+    Multiaddr,
+};
+
+/// Listens on `addr`, returns the listener and the address it reported.
+pub async fn listen<T>(transport: T, addr: Multiaddr) -> (T::Listener, Multiaddr)
+where
+    T: Transport,
+    T::Listener: Unpin,
+{
+    let mut listener = match transport.listen_on(addr) {
+        Ok(listener) => listener,
+        Err(_) => panic!("failed to listen"),
+    };
+    match listener.next().await {
+        Some(Ok(ListenerEvent::NewAddress(addr))) => (listener, addr),
+        _ => panic!("expected NewAddress event"),
+    }
+}
+
+/// Waits for the next inbound connection, returns its upgrade.
+pub async fn next_upgrade<L, U, E>(listener: &mut L) -> U
+where
+    L: Stream<Item = Result<ListenerEvent<U, E>, E>> + Unpin,
+{
+    match listener.next().await {
+        Some(Ok(ListenerEvent::Upgrade { upgrade, .. })) => upgrade,
+        _ => panic!("expected Upgrade event"),
+    }
+}
+
+/// Dials `listener` with `dialer` over a new `/memory` address, returns the
+/// results of the dialer's and the listener's upgrades.
+pub async fn connect_memory<T>(
+    dialer: T,
+    listener: T,
+) -> (Result<T::Output, T::Error>, Result<T::Output, T::Error>)
+where
+    T: Transport,
+    T::Listener: Unpin,
+{
+    let addr: Multiaddr = format!("/memory/{}", rand::random::<u64>())
+        .parse()
+        .unwrap();
+    let (mut listener, addr) = listen(listener, addr).await;
+    let dial = match dialer.dial(addr) {
+        Ok(dial) => dial,
+        Err(_) => panic!("failed to dial"),
+    };
+    let accept = async { next_upgrade(&mut listener).await.await };
+    future::join(dial, accept).await
+}
@@ -0,0 +1,260 @@
+//! Onion services configured in a torrc.
+//!
+//! Each `HiddenServiceDir` starts a service, the `HiddenServicePort` lines
+//! that follow it give the service's ports as `VIRTPORT [TARGET]`, see the
+//! tor(1) man page. Tor writes the service's address to `hostname` in its
+//! directory, which together with the ports gives the listener's onion map.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use libp2p::Multiaddr;
+use log::warn;
+
+use crate::onion::OnionAddress;
+
+/// An onion service configured with `HiddenServiceDir`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HiddenService {
+    /// Directory holding the service's keys and `hostname`.
+    pub dir: PathBuf,
+    /// The service's `HiddenServicePort` lines.
+    pub ports: Vec<HiddenServicePort>,
+}
+
+impl HiddenService {
+    /// Reads the service's address from `hostname`, with port 0.
+    pub fn hostname(&self) -> io::Result<OnionAddress> {
+        let path = self.dir.join("hostname");
+        let hostname = fs::read_to_string(&path)?;
+        hostname.trim().parse().map_err(|e| {
+            let msg = format!("{}: {}", path.display(), e);
+            io::Error::new(io::ErrorKind::InvalidData, msg)
+        })
+    }
+}
+
+/// A `HiddenServicePort` line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HiddenServicePort {
+    /// The port clients connect to.
+    pub virtual_port: u16,
+    /// Where Tor forwards connections to.
+    pub target: PortTarget,
+}
+
+/// Where Tor forwards an onion service port to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PortTarget {
+    /// A TCP socket.
+    Tcp(SocketAddr),
+    /// A Unix-domain socket.
+    Unix(PathBuf),
+}
+
+/// Reads the onion services configured in the torrc at `path`.
+pub fn read(path: &Path) -> io::Result<Vec<HiddenService>> {
+    parse(&fs::read_to_string(path)?)
+}
+
+/// Parses the onion services configured in a torrc, ignoring all other
+/// options.
+pub fn parse(torrc: &str) -> io::Result<Vec<HiddenService>> {
+    let mut services: Vec<HiddenService> = Vec::new();
+
+    for (i, line) in torrc.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        let (keyword, value) = match line.find(char::is_whitespace) {
+            Some(end) => (&line[..end], line[end..].trim()),
+            None => (line, ""),
+        };
+        let invalid = |what: &str| {
+            let msg = format!("torrc line {}: {}: {}", i + 1, what, line);
+            io::Error::new(io::ErrorKind::InvalidData, msg)
+        };
+
+        if keyword.eq_ignore_ascii_case("HiddenServiceDir") {
+            if value.is_empty() {
+                return Err(invalid("missing directory"));
+            }
+            services.push(HiddenService {
+                dir: PathBuf::from(unquote(value)),
+                ports: Vec::new(),
+            });
+        } else if keyword.eq_ignore_ascii_case("HiddenServicePort") {
+            let service = services
+                .last_mut()
+                .ok_or_else(|| invalid("HiddenServicePort before HiddenServiceDir"))?;
+            let port = parse_port(value).ok_or_else(|| invalid("invalid port"))?;
+            service.ports.push(port);
+        }
+    }
+
+    Ok(services)
+}
+
+/// The onion map for `TorTokioTcpConfig::onion_map`, mapping each port of
+/// each service to the local port Tor forwards it to. Reads the services'
+/// `hostname` files.
+///
+/// Only ports forwarded to localhost over TCP can be listened on, others
+/// are skipped.
+pub fn onion_port_map(services: &[HiddenService]) -> io::Result<HashMap<Multiaddr, u16>> {
+    let mut map = HashMap::new();
+
+    for service in services {
+        let host = service.hostname()?.host();
+        for port in &service.ports {
+            let local = match &port.target {
+                PortTarget::Tcp(addr) if addr.ip().is_loopback() => addr.port(),
+                target => {
+                    warn!(
+                        "skipping {} port {} forwarded to {:?}",
+                        host, port.virtual_port, target
+                    );
+                    continue;
+                }
+            };
+            let onion: OnionAddress = format!("{}:{}", host, port.virtual_port)
+                .parse()
+                .map_err(io::Error::from)?;
+            map.insert(Multiaddr::empty().with(onion.to_protocol()), local);
+        }
+    }
+
+    Ok(map)
+}
+
+// Parses `VIRTPORT [TARGET]`, the target is `PORT`, `ADDR:PORT` or
+// `unix:PATH` and defaults to the virtual port on localhost.
+fn parse_port(value: &str) -> Option<HiddenServicePort> {
+    let mut parts = value.split_whitespace();
+    let virtual_port = parts.next()?.parse().ok()?;
+    let target = match parts.next() {
+        None => PortTarget::Tcp(localhost(virtual_port)),
+        Some(target) => match target.strip_prefix("unix:") {
+            Some(path) => PortTarget::Unix(PathBuf::from(unquote(path))),
+            None => PortTarget::Tcp(parse_target(target)?),
+        },
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+
+    Some(HiddenServicePort {
+        virtual_port,
+        target,
+    })
+}
+
+fn parse_target(target: &str) -> Option<SocketAddr> {
+    if let Ok(port) = target.parse() {
+        return Some(localhost(port));
+    }
+    if let Some(port) = target.strip_prefix("localhost:") {
+        return port.parse().ok().map(localhost);
+    }
+    target.parse().ok()
+}
+
+fn localhost(port: u16) -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+}
+
+fn unquote(s: &str) -> &str {
+    s.strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .unwrap_or(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TORRC: &str = "\
+        ## Comment\n\
+        ControlPort 9051\n\
+        HiddenServiceDir /var/lib/tor/hidden_service/\n\
+        HiddenServicePort 7 127.0.0.1:7777\n\
+        \n\
+        hiddenservicedir /var/lib/tor/other_hidden_service/ # trailing comment\n\
+        HiddenServiceVersion 3\n\
+        HiddenServicePort 80\n\
+        HiddenServicePort 22 [::1]:2222\n\
+        HiddenServicePort 443 unix:/run/https.sock\n";
+
+    #[test]
+    fn parses_multiple_services_and_ports() {
+        let services = parse(TORRC).unwrap();
+
+        assert_eq!(services.len(), 2);
+        assert_eq!(services[0].dir, Path::new("/var/lib/tor/hidden_service/"));
+        assert_eq!(
+            services[0].ports,
+            vec![HiddenServicePort {
+                virtual_port: 7,
+                target: PortTarget::Tcp("127.0.0.1:7777".parse().unwrap()),
+            }]
+        );
+        assert_eq!(
+            services[1].dir,
+            Path::new("/var/lib/tor/other_hidden_service/")
+        );
+        let targets: Vec<_> = services[1].ports.iter().map(|p| p.target.clone()).collect();
+        assert_eq!(
+            targets,
+            vec![
+                PortTarget::Tcp("127.0.0.1:80".parse().unwrap()),
+                PortTarget::Tcp("[::1]:2222".parse().unwrap()),
+                PortTarget::Unix(PathBuf::from("/run/https.sock")),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_port_without_service() {
+        let err = parse("HiddenServicePort 7 127.0.0.1:7777\n").unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_invalid_port() {
+        assert!(parse("HiddenServiceDir /tmp/hs\nHiddenServicePort seven\n").is_err());
+    }
+
+    #[test]
+    fn builds_onion_map_from_hostname() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("hidden_service");
+        let keypair = libp2p::identity::ed25519::Keypair::generate();
+        let address = crate::keys::write_hidden_service_dir(&dir, &keypair, 7).unwrap();
+        let torrc = format!(
+            "HiddenServiceDir {dir}\n\
+             HiddenServicePort 7 127.0.0.1:7777\n\
+             HiddenServicePort 8 localhost:8888\n\
+             HiddenServicePort 9 unix:/run/nine.sock\n",
+            dir = dir.display()
+        );
+
+        let map = onion_port_map(&parse(&torrc).unwrap()).unwrap();
+
+        let onion = |port| {
+            let addr = OnionAddress::from_public_key(
+                &libp2p::identity::PublicKey::Ed25519(keypair.public()),
+                port,
+            );
+            Multiaddr::empty().with(addr.unwrap().to_protocol())
+        };
+        assert_eq!(map.len(), 2);
+        assert_eq!(
+            map.get(&Multiaddr::empty().with(address.to_protocol())),
+            Some(&7777)
+        );
+        assert_eq!(map.get(&onion(8)), Some(&8888));
+    }
+}
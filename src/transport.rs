@@ -27,19 +27,17 @@
 
 use anyhow::Result;
 use data_encoding::BASE32;
-use futures::{
-    future::{self, Ready},
-    prelude::*,
-};
+use futures::{future::BoxFuture, prelude::*};
 use futures_timer::Delay;
-use get_if_addrs::{get_if_addrs, IfAddr};
-use ipnet::{IpNet, Ipv4Net, Ipv6Net};
-use libp2p::core::{
-    multiaddr::{Multiaddr, Protocol},
-    transport::{ListenerEvent, TransportError},
-    Transport,
+use libp2p::{
+    core::{
+        multiaddr::{Multiaddr, Protocol},
+        transport::{ListenerEvent, TransportError},
+        Transport,
+    },
+    pnet::PreSharedKey,
 };
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
 use socket2::{Domain, Socket, Type};
 use std::{
     net::{Ipv4Addr, SocketAddr, SocketAddrV4, IpAddr},
@@ -53,15 +51,22 @@ use std::{
 };
 use tokio::net::{TcpListener, TcpStream};
 
-/// Default port for the Tor SOCKS5 proxy.
-const DEFAULT_SOCKS_PORT: u16 = 9050;
+use crate::{
+    endpoint::{LocalEndpoint, LocalStream},
+    limits::{AcceptRate, ConnectionCounter, ConnectionGuard, TokenBucket, DEFAULT_BACKLOG},
+    onion::{OnionAddress, OnionAddressError, OnionV2Policy},
+    peer::{self, PeerPolicy},
+    proxy_protocol::{self, ProxyHeader},
+    security::Security,
+    socks::{self, IsolationPolicy, TargetAddr, DEFAULT_SOCKS_PORT},
+};
 
 /// Represents the configuration for a TCP/IP transport capability for libp2p.
 ///
 /// The TCP sockets created by libp2p will need to be progressed by running the futures and streams
 /// obtained by libp2p through the tokio reactor.
 #[cfg_attr(docsrs, doc(cfg(feature = $feature_name)))]
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone)]
 pub struct TorTokioTcpConfig {
     /// How long a listener should sleep after receiving an error, before trying again.
     sleep_on_error: Duration,
@@ -71,8 +76,38 @@ pub struct TorTokioTcpConfig {
     nodelay: Option<bool>,
     /// Map of Multiaddr to port number for local socket.
     onion_map: HashMap<Multiaddr, u16>,
-    /// Tor SOCKS5 proxy port number.
-    socks_port: u16,
+    /// Tor SOCKS5 proxy endpoint.
+    socks_proxy: LocalEndpoint,
+    /// Which dials may share a Tor circuit.
+    isolation: IsolationPolicy,
+    /// Resolve host names with Tor's RESOLVE extension before connecting.
+    tor_resolve: bool,
+    /// Allow dialing non-onion addresses via Tor exit relays.
+    allow_clearnet: bool,
+    /// What to do when dialing a deprecated v2 onion address.
+    onion_v2: OnionV2Policy,
+    /// Expect a HAProxy PROXY v1 header on inbound connections.
+    proxy_protocol: bool,
+    /// Require dialed onion services to authenticate with the onion's key.
+    onion_identity: bool,
+    /// Authentication protocols offered.
+    security: Security,
+    /// Swarm key of the private network we belong to, if any.
+    psk: Option<PreSharedKey>,
+    /// Which authenticated peers may connect.
+    peer_policy: PeerPolicy,
+    /// Maximum number of concurrent inbound connections, or `None` for no limit.
+    max_inbound: Option<usize>,
+    /// Maximum rate of accepted inbound connections, or `None` for no limit.
+    accept_rate: Option<AcceptRate>,
+    /// Backlog of the listening socket.
+    backlog: i32,
+}
+
+impl Default for TorTokioTcpConfig {
+    fn default() -> Self {
+        TorTokioTcpConfig::new()
+    }
 }
 
 impl TorTokioTcpConfig {
@@ -83,7 +118,19 @@ impl TorTokioTcpConfig {
             ttl: None,
             nodelay: None,
             onion_map: HashMap::new(),
-            socks_port: DEFAULT_SOCKS_PORT,
+            socks_proxy: LocalEndpoint::localhost(DEFAULT_SOCKS_PORT),
+            isolation: IsolationPolicy::default(),
+            tor_resolve: false,
+            allow_clearnet: false,
+            onion_v2: OnionV2Policy::default(),
+            proxy_protocol: false,
+            onion_identity: false,
+            security: Security::default(),
+            psk: None,
+            peer_policy: PeerPolicy::default(),
+            max_inbound: None,
+            accept_rate: None,
+            backlog: DEFAULT_BACKLOG,
         }
     }
 
@@ -105,40 +152,193 @@ impl TorTokioTcpConfig {
         self
     }
 
-    /// Sets the Tor SOCKS5 proxy port number.
+    /// Sets the Tor SOCKS5 proxy port number, the proxy is expected on localhost.
     pub fn socks_port(mut self, port: u16) -> Self {
-        self.socks_port = port;
+        self.socks_proxy = LocalEndpoint::localhost(port);
+        self
+    }
+
+    /// Sets the Tor SOCKS5 proxy endpoint, either a TCP socket or a Unix-domain socket.
+    pub fn socks_proxy(mut self, endpoint: LocalEndpoint) -> Self {
+        self.socks_proxy = endpoint;
+        self
+    }
+
+    /// Sets the circuit isolation policy used when dialing.
+    pub fn isolation(mut self, policy: IsolationPolicy) -> Self {
+        self.isolation = policy;
         self
     }
+
+    /// Resolve host names to IP addresses using Tor's RESOLVE extension and
+    /// connect to the address, instead of passing the name to Tor's CONNECT.
+    ///
+    /// Either way name resolution happens inside Tor, never via the system resolver.
+    pub fn tor_resolve(mut self, value: bool) -> Self {
+        self.tor_resolve = value;
+        self
+    }
+
+    /// Allow dialing `/ip4`, `/ip6` and `/dns*` addresses, these connections
+    /// leave the Tor network at an exit relay. Only onion addresses are dialed
+    /// by default.
+    pub fn allow_clearnet(mut self, value: bool) -> Self {
+        self.allow_clearnet = value;
+        self
+    }
+
+    /// Sets the policy for dialing deprecated v2 `/onion` addresses.
+    pub fn onion_v2(mut self, policy: OnionV2Policy) -> Self {
+        self.onion_v2 = policy;
+        self
+    }
+
+    /// Expect inbound connections to start with a HAProxy PROXY v1 header, as
+    /// sent by Tor when configured with `HiddenServiceExportCircuitID haproxy`.
+    ///
+    /// The header is read when the connection is upgraded, the client's
+    /// circuit ID is then available from [`TokioTcpTransStream::circuit_id`].
+    pub fn proxy_protocol(mut self, value: bool) -> Self {
+        self.proxy_protocol = value;
+        self
+    }
+
+    /// Requires the peer reached via a dialed onion service to authenticate
+    /// with the service's own ed25519 key, see `onion::onion_multiaddr`.
+    pub fn verify_onion_identity(mut self, value: bool) -> Self {
+        self.onion_identity = value;
+        self
+    }
+
+    /// Whether dialed onion services must authenticate with their own key.
+    pub fn verifies_onion_identity(&self) -> bool {
+        self.onion_identity
+    }
+
+    /// Sets the authentication protocols offered when dialing and accepted
+    /// when listening.
+    pub fn security(mut self, security: Security) -> Self {
+        self.security = security;
+        self
+    }
+
+    /// The authentication protocols offered.
+    pub fn security_protocols(&self) -> Security {
+        self.security
+    }
+
+    /// Only connects to peers holding the swarm key `psk`, see [`crate::psk`].
+    pub fn private_network(mut self, psk: PreSharedKey) -> Self {
+        self.psk = Some(psk);
+        self
+    }
+
+    /// The swarm key of our private network, if any.
+    pub fn swarm_key(&self) -> Option<PreSharedKey> {
+        self.psk
+    }
+
+    /// Sets which authenticated peers may connect to us, others are closed
+    /// before any protocol runs.
+    pub fn peer_policy(mut self, policy: PeerPolicy) -> Self {
+        self.peer_policy = policy;
+        self
+    }
+
+    /// Which authenticated peers may connect.
+    pub fn peer_access(&self) -> &PeerPolicy {
+        &self.peer_policy
+    }
+
+    /// Sets the maximum number of concurrent inbound connections, further
+    /// connections are closed as soon as they are accepted. Fails if `max` is
+    /// zero.
+    pub fn max_inbound(mut self, max: usize) -> io::Result<Self> {
+        if max == 0 {
+            let msg = "maximum inbound connections must be at least one";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        self.max_inbound = Some(max);
+        Ok(self)
+    }
+
+    /// Limits the rate at which inbound connections are accepted to
+    /// `per_second` on average, allowing bursts of up to `burst` connections.
+    /// Connections beyond the rate wait in the socket's backlog. Fails unless
+    /// `per_second` is finite and positive and `burst` at least one.
+    pub fn accept_rate(mut self, per_second: f64, burst: u32) -> io::Result<Self> {
+        self.accept_rate = Some(AcceptRate::new(per_second, burst)?);
+        Ok(self)
+    }
+
+    /// Sets the backlog of the listening socket. Fails unless `backlog` is
+    /// positive.
+    pub fn backlog(mut self, backlog: i32) -> io::Result<Self> {
+        if backlog <= 0 {
+            let msg = format!("listen backlog must be positive: {}", backlog);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        self.backlog = backlog;
+        Ok(self)
+    }
+
+    /// The local socket Tor forwards connections for `onion` to, if configured.
+    ///
+    /// This is diagnostic information only, the listener reports the onion
+    /// address as its external address.
+    pub fn local_addr(&self, onion: &Multiaddr) -> Option<SocketAddr> {
+        let port = self.onion_map.get(onion)?;
+        Some(SocketAddr::V4(SocketAddrV4::new(
+            Ipv4Addr::LOCALHOST,
+            *port,
+        )))
+    }
+
+    /// Validates an onion address before it is handed to Tor.
+    fn check_onion(&self, addr: &Multiaddr) -> Result<(), OnionAddressError> {
+        let onion = match addr.iter().next().map(|p| OnionAddress::try_from(&p)) {
+            Some(Ok(onion)) => onion,
+            Some(Err(OnionAddressError::NotOnion)) | None => return Ok(()),
+            Some(Err(e)) => return Err(e),
+        };
+
+        if let OnionAddress::V2 { .. } = onion {
+            match self.onion_v2 {
+                OnionV2Policy::Reject => return Err(OnionAddressError::V2Rejected),
+                OnionV2Policy::Warn => warn!("dialing deprecated v2 onion address: {}", onion),
+                OnionV2Policy::Allow => {}
+            }
+        }
+
+        Ok(())
+    }
 }
 
 type Listener<TUpgrade, TError> =
     Pin<Box<dyn Stream<Item = Result<ListenerEvent<TUpgrade, TError>, TError>> + Send>>;
 
+/// Completes an inbound connection, reading its PROXY header if configured.
+type Upgrade = BoxFuture<'static, Result<TokioTcpTransStream, io::Error>>;
+
 impl Transport for TorTokioTcpConfig {
     type Output = TokioTcpTransStream;
     type Error = io::Error;
     type Listener = Listener<Self::ListenerUpgrade, Self::Error>;
-    type ListenerUpgrade = Ready<Result<Self::Output, Self::Error>>;
+    type ListenerUpgrade = Upgrade;
     type Dial = Pin<Box<dyn Future<Output = Result<TokioTcpTransStream, io::Error>> + Send>>;
 
     fn listen_on(self, addr: Multiaddr) -> Result<Self::Listener, TransportError<Self::Error>> {
-        let port = match self.onion_map.get(&addr) {
-            Some(port) => port,
+        let socket_addr = match self.local_addr(&addr) {
+            Some(socket_addr) => socket_addr,
             None => return Err(TransportError::MultiaddrNotSupported(addr)),
         };
-        let socket_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, *port));
 
         async fn do_listen(
             cfg: TorTokioTcpConfig,
+            onion: Multiaddr,
             socket_addr: SocketAddr,
         ) -> Result<
-            impl Stream<
-                Item = Result<
-                    ListenerEvent<Ready<Result<TokioTcpTransStream, io::Error>>, io::Error>,
-                    io::Error,
-                >,
-            >,
+            impl Stream<Item = Result<ListenerEvent<Upgrade, io::Error>, io::Error>>,
             io::Error,
         > {
             let socket = if socket_addr.is_ipv4() {
@@ -160,95 +360,119 @@ impl Transport for TorTokioTcpConfig {
                 socket.set_reuse_address(true)?;
             }
             socket.bind(&socket_addr.into())?;
-            socket.listen(1024)?; // we may want to make this configurable
+            socket.listen(cfg.backlog)?;
 
             let listener = <TcpListener>::try_from(socket.into_tcp_listener())
                 .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
 
             let local_addr = listener.local_addr()?;
-            let port = local_addr.port();
-
-            // Determine all our listen addresses which is either a single local IP address
-            // or (if a wildcard IP address was used) the addresses of all our interfaces,
-            // as reported by `get_if_addrs`.
-            let addrs = if socket_addr.ip().is_unspecified() {
-                let addrs = host_addresses(port)?;
-                debug!(
-                    "Listening on {:?}",
-                    addrs.iter().map(|(_, _, ma)| ma).collect::<Vec<_>>()
-                );
-                Addresses::Many(addrs)
-            } else {
-                let ma = ip_to_multiaddr(local_addr.ip(), port);
-                debug!("Listening on {:?}", ma);
-                Addresses::One(ma)
-            };
+            let local_addr = ip_to_multiaddr(local_addr.ip(), local_addr.port());
+            debug!("Listening on {} via {}", onion, local_addr);
 
-            // Generate `NewAddress` events for each new `Multiaddr`.
-            let pending = match addrs {
-                Addresses::One(ref ma) => {
-                    let event = ListenerEvent::NewAddress(ma.clone());
-                    let mut list = VecDeque::new();
-                    list.push_back(Ok(event));
-                    list
-                }
-                Addresses::Many(ref aa) => aa
-                    .iter()
-                    .map(|(_, _, ma)| ma)
-                    .cloned()
-                    .map(ListenerEvent::NewAddress)
-                    .map(Result::Ok)
-                    .collect::<VecDeque<_>>(),
-            };
+            // Peers can only reach us via Tor so the onion address is the only
+            // address we report, the local addresses are of no use to them.
+            let mut pending = VecDeque::new();
+            pending.push_back(Ok(ListenerEvent::NewAddress(onion.clone())));
 
             let listen_stream = TokioTcpListenStream {
                 stream: listener,
                 pause: None,
                 pause_duration: cfg.sleep_on_error,
-                port,
-                addrs,
+                onion,
+                local_addr,
                 pending,
+                connections: ConnectionCounter::default(),
+                bucket: cfg.accept_rate.map(TokenBucket::new),
                 config: cfg,
             };
 
             Ok(stream::unfold(listen_stream, |s| s.next().map(Some)))
         }
 
-        Ok(Box::pin(do_listen(self, socket_addr).try_flatten_stream()))
+        Ok(Box::pin(
+            do_listen(self, addr, socket_addr).try_flatten_stream(),
+        ))
     }
 
     fn dial(self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
-        let dest = tor_address_string(addr.clone())
-            .ok_or_else(|| TransportError::MultiaddrNotSupported(addr))?;
+        if let Err(e) = self.check_onion(&addr) {
+            debug!("refusing to dial {}: {}", addr, e);
+            return Err(TransportError::Other(e.into()));
+        }
+
+        let dest = tor_address_string(addr.clone()).and_then(|s| s.parse::<TargetAddr>().ok());
+        let dest = match dest {
+            Some(dest) if dest.is_onion() || self.allow_clearnet => dest,
+            _ => return Err(TransportError::MultiaddrNotSupported(addr)),
+        };
         debug!("dest: {}", dest);
 
+        // Isolate on the peer's identity if we know it, otherwise on the host,
+        // all ports of a peer share its circuit.
+        let peer = match peer::expected_peer_id(&addr) {
+            Some(id) => id.to_base58(),
+            None => dest.host(),
+        };
+
         async fn do_dial(
             cfg: TorTokioTcpConfig,
-            dest: String,
+            dest: TargetAddr,
+            peer: String,
         ) -> Result<TokioTcpTransStream, io::Error> {
-            info!("connecting to Tor proxy ...");
-            let stream = crate::connect_tor_socks_proxy(dest, cfg.socks_port)
-                .await
-                .map_err(|e| io::Error::new(io::ErrorKind::ConnectionRefused, e))?;
+            let auth = cfg.isolation.credentials(&peer);
+
+            let dest = match dest {
+                TargetAddr::Domain(host, port) if cfg.tor_resolve && !dest.is_onion() => {
+                    info!("resolving {} via Tor ...", host);
+                    let mut stream = socks::connect_proxy(&cfg.socks_proxy).await?;
+                    let ip = socks::resolve(&mut stream, &host, auth.as_ref()).await?;
+                    debug!("resolved {} to {}", host, ip);
+                    TargetAddr::Ip(SocketAddr::new(ip, port))
+                }
+                dest => dest,
+            };
+
+            info!("connecting to Tor proxy at {} ...", cfg.socks_proxy);
+            let stream =
+                crate::connect_tor_socks_proxy(&dest, &cfg.socks_proxy, auth.as_ref()).await?;
             info!("connection established");
 
-            apply_config(&cfg, &stream)?;
+            if let Some(stream) = stream.as_tcp() {
+                apply_config(&cfg, stream)?;
+            }
 
-            Ok(TokioTcpTransStream { inner: stream })
+            Ok(TokioTcpTransStream {
+                inner: stream,
+                proxy_header: None,
+                _guard: None,
+            })
         }
 
-        Ok(Box::pin(do_dial(self, dest)))
+        Ok(Box::pin(do_dial(self, dest, peer)))
     }
 }
 
-// Tor expects address in form: ADDR.onion:PORT
+// Tor expects address in form: ADDR.onion:PORT, host names are passed to Tor
+// unresolved so that no DNS query is made outside of Tor. IP addresses are
+// passed as is, Tor connects to them via an exit relay. A trailing `/p2p`
+// component is ignored here, it is checked after authentication.
 fn tor_address_string(mut multi: Multiaddr) -> Option<String> {
+    if let Some(Protocol::P2p(_)) = multi.iter().last() {
+        multi.pop();
+    }
     let (encoded, port) = match multi.pop()? {
-        Protocol::Onion(addr, port) => {
-            (BASE32.encode(addr.as_ref()), port)
-        }
-        Protocol::Onion3(addr) => {
-            (BASE32.encode(addr.hash()), addr.port())
+        Protocol::Onion(addr, port) => (BASE32.encode(addr.as_ref()), port),
+        Protocol::Onion3(addr) => (BASE32.encode(addr.hash()), addr.port()),
+        Protocol::Tcp(port) => {
+            let host = match (multi.pop()?, multi.pop()) {
+                (Protocol::Dns(host), None)
+                | (Protocol::Dns4(host), None)
+                | (Protocol::Dns6(host), None) => host.into_owned(),
+                (Protocol::Ip4(ip), None) => ip.to_string(),
+                (Protocol::Ip6(ip), None) => format!("[{}]", ip),
+                _ => return None,
+            };
+            return Some(format!("{}:{}", host, port));
         }
         _ => return None,
     };
@@ -265,24 +489,23 @@ pub struct TokioTcpListenStream {
     pause: Option<Delay>,
     /// How long to pause after an error.
     pause_duration: Duration,
-    /// The port which we use as our listen port in listener event addresses.
-    port: u16,
-    /// The set of known addresses.
-    addrs: Addresses,
+    /// The onion address Tor forwards to this listener, reported as our address.
+    onion: Multiaddr,
+    /// The local address we are bound to, for diagnostics only.
+    local_addr: Multiaddr,
     /// Temporary buffer of listener events.
-    pending: Buffer<TokioTcpTransStream>,
+    pending: Buffer,
+    /// Open inbound connections.
+    connections: ConnectionCounter,
+    /// Accept rate limiter, if configured.
+    bucket: Option<TokenBucket>,
     /// Original configuration.
     config: TorTokioTcpConfig,
 }
 
 impl TokioTcpListenStream {
     /// Takes ownership of the listener, and returns the next incoming event and the listener.
-    async fn next(
-        mut self,
-    ) -> (
-        Result<ListenerEvent<Ready<Result<TokioTcpTransStream, io::Error>>, io::Error>, io::Error>,
-        Self,
-    ) {
+    async fn next(mut self) -> (Result<ListenerEvent<Upgrade, io::Error>, io::Error>, Self) {
         loop {
             if let Some(event) = self.pending.pop_front() {
                 return (event, self);
@@ -292,6 +515,13 @@ impl TokioTcpListenStream {
                 let _ = pause.await;
             }
 
+            if let Some(bucket) = self.bucket.as_mut() {
+                while let Err(wait) = bucket.take() {
+                    trace!("accept rate exceeded, waiting {:?}", wait);
+                    Delay::new(wait).await;
+                }
+            }
+
             // TODO: do we get the peer_addr at the same time?
             let (sock, _) = match self.stream.accept().await {
                 Ok(s) => s,
@@ -302,6 +532,22 @@ impl TokioTcpListenStream {
                 }
             };
 
+            // Dropping the socket closes the connection.
+            let guard = match self.config.max_inbound {
+                Some(max) => match self.connections.try_acquire(max) {
+                    Some(guard) => Some(guard),
+                    None => {
+                        debug!(
+                            "refusing inbound connection, {} of {} connections open",
+                            self.connections.count(),
+                            max
+                        );
+                        continue;
+                    }
+                },
+                None => None,
+            };
+
             let sock_addr = match sock.peer_addr() {
                 Ok(addr) => addr,
                 Err(err) => {
@@ -310,49 +556,58 @@ impl TokioTcpListenStream {
                 }
             };
 
-            let local_addr = match sock.local_addr() {
-                Ok(sock_addr) => {
-                    if let Addresses::Many(ref mut addrs) = self.addrs {
-                        if let Err(err) = check_for_interface_changes(
-                            &sock_addr,
-                            self.port,
-                            addrs,
-                            &mut self.pending,
-                        ) {
-                            return (Ok(ListenerEvent::Error(err)), self);
-                        }
-                    }
-                    ip_to_multiaddr(sock_addr.ip(), sock_addr.port())
-                }
-                Err(err) => {
-                    debug!("Failed to get local address of incoming socket: {:?}", err);
-                    continue;
-                }
-            };
-
             let remote_addr = ip_to_multiaddr(sock_addr.ip(), sock_addr.port());
-
-            match apply_config(&self.config, &sock) {
-                Ok(()) => {
-                    trace!("Incoming connection from {} at {}", remote_addr, local_addr);
-                    self.pending.push_back(Ok(ListenerEvent::Upgrade {
-                        upgrade: future::ok(TokioTcpTransStream { inner: sock }),
-                        local_addr,
-                        remote_addr,
-                    }))
-                }
-                Err(err) => {
+            let local_addr = self.onion.clone();
+            trace!(
+                "Incoming connection from {} at {} via {}",
+                remote_addr,
+                local_addr,
+                self.local_addr
+            );
+
+            // The header is read as part of the upgrade, a client that sends
+            // nothing must not hold up accepting other connections.
+            let config = self.config.clone();
+            let peer = remote_addr.clone();
+            let upgrade = async move {
+                let mut sock = sock;
+                let proxy_header = if config.proxy_protocol {
+                    let header =
+                        proxy_protocol::read_header(&mut sock, proxy_protocol::HEADER_TIMEOUT)
+                            .await?;
+                    if let Some(circuit) = header.circuit_id() {
+                        debug!(
+                            "Incoming connection from {} on Tor circuit {}",
+                            peer, circuit
+                        );
+                    }
+                    Some(header)
+                } else {
+                    None
+                };
+                apply_config(&config, &sock)?;
+
+                Ok(TokioTcpTransStream {
+                    inner: LocalStream::Tcp(sock),
+                    proxy_header,
+                    _guard: guard,
+                })
+            }
+            .inspect_err({
+                let remote_addr = remote_addr.clone();
+                move |err| {
                     debug!(
                         "Error upgrading incoming connection from {}: {:?}",
                         remote_addr, err
-                    );
-                    self.pending.push_back(Ok(ListenerEvent::Upgrade {
-                        upgrade: future::err(err),
-                        local_addr,
-                        remote_addr,
-                    }))
+                    )
                 }
-            }
+            });
+
+            self.pending.push_back(Ok(ListenerEvent::Upgrade {
+                upgrade: upgrade.boxed(),
+                local_addr,
+                remote_addr,
+            }));
         }
     }
 }
@@ -361,15 +616,35 @@ impl TokioTcpListenStream {
 #[cfg_attr(docsrs, doc(cfg(feature = $feature_name)))]
 #[derive(Debug)]
 pub struct TokioTcpTransStream {
-    inner: TcpStream,
+    inner: LocalStream,
+    /// PROXY header Tor sent ahead of an inbound connection, if expected.
+    proxy_header: Option<ProxyHeader>,
+    /// Slot in the listener's connection limit, released on drop.
+    _guard: Option<ConnectionGuard>,
+}
+
+impl TokioTcpTransStream {
+    /// The PROXY header Tor sent ahead of this inbound connection, if
+    /// `TorTokioTcpConfig::proxy_protocol` is set.
+    pub fn proxy_header(&self) -> Option<&ProxyHeader> {
+        self.proxy_header.as_ref()
+    }
+
+    /// The Tor circuit this inbound connection arrived on, from its PROXY
+    /// header.
+    pub fn circuit_id(&self) -> Option<u32> {
+        self.proxy_header.as_ref().and_then(ProxyHeader::circuit_id)
+    }
 }
 
 impl Drop for TokioTcpTransStream {
     fn drop(&mut self) {
-        if let Ok(addr) = self.inner.peer_addr() {
-            debug!("Dropped TCP connection to {:?}", addr);
-        } else {
-            debug!("Dropped TCP connection to undeterminate peer");
+        match &self.inner {
+            LocalStream::Tcp(stream) => match stream.peer_addr() {
+                Ok(addr) => debug!("Dropped TCP connection to {:?}", addr),
+                Err(_) => debug!("Dropped TCP connection to undeterminate peer"),
+            },
+            LocalStream::Unix(_) => debug!("Dropped Unix socket connection to Tor proxy"),
         }
     }
 }
@@ -425,104 +700,13 @@ fn ip_to_multiaddr(ip: IpAddr, port: u16) -> Multiaddr {
     Multiaddr::from_iter(it)
 }
 
-// Collect all local host addresses and use the provided port number as listen port.
-fn host_addresses(port: u16) -> io::Result<Vec<(IpAddr, IpNet, Multiaddr)>> {
-    let mut addrs = Vec::new();
-    for iface in get_if_addrs()? {
-        let ip = iface.ip();
-        let ma = ip_to_multiaddr(ip, port);
-        let ipn = match iface.addr {
-            IfAddr::V4(ip4) => {
-                let prefix_len = (!u32::from_be_bytes(ip4.netmask.octets())).leading_zeros();
-                let ipnet = Ipv4Net::new(ip4.ip, prefix_len as u8)
-                    .expect("prefix_len is the number of bits in a u32, so can not exceed 32");
-                IpNet::V4(ipnet)
-            }
-            IfAddr::V6(ip6) => {
-                let prefix_len = (!u128::from_be_bytes(ip6.netmask.octets())).leading_zeros();
-                let ipnet = Ipv6Net::new(ip6.ip, prefix_len as u8)
-                    .expect("prefix_len is the number of bits in a u128, so can not exceed 128");
-                IpNet::V6(ipnet)
-            }
-        };
-        addrs.push((ip, ipn, ma))
-    }
-    Ok(addrs)
-}
-
-/// Listen address information.
-#[derive(Debug)]
-enum Addresses {
-    /// A specific address is used to listen.
-    One(Multiaddr),
-    /// A set of addresses is used to listen.
-    Many(Vec<(IpAddr, IpNet, Multiaddr)>),
-}
-
-type Buffer<T> = VecDeque<Result<ListenerEvent<Ready<Result<T, io::Error>>, io::Error>, io::Error>>;
-
-// If we listen on all interfaces, find out to which interface the given
-// socket address belongs. In case we think the address is new, check
-// all host interfaces again and report new and expired listen addresses.
-fn check_for_interface_changes<T>(
-    socket_addr: &SocketAddr,
-    listen_port: u16,
-    listen_addrs: &mut Vec<(IpAddr, IpNet, Multiaddr)>,
-    pending: &mut Buffer<T>,
-) -> Result<(), io::Error> {
-    // Check for exact match:
-    if listen_addrs.iter().any(|(ip, ..)| ip == &socket_addr.ip()) {
-        return Ok(());
-    }
-
-    // No exact match => check netmask
-    if listen_addrs
-        .iter()
-        .any(|(_, net, _)| net.contains(&socket_addr.ip()))
-    {
-        return Ok(());
-    }
-
-    // The local IP address of this socket is new to us.
-    // We check for changes in the set of host addresses and report new
-    // and expired addresses.
-    //
-    // TODO: We do not detect expired addresses unless there is a new address.
-    let old_listen_addrs = std::mem::replace(listen_addrs, host_addresses(listen_port)?);
-
-    // Check for addresses no longer in use.
-    for (ip, _, ma) in old_listen_addrs.iter() {
-        if listen_addrs.iter().find(|(i, ..)| i == ip).is_none() {
-            debug!("Expired listen address: {}", ma);
-            pending.push_back(Ok(ListenerEvent::AddressExpired(ma.clone())));
-        }
-    }
-
-    // Check for new addresses.
-    for (ip, _, ma) in listen_addrs.iter() {
-        if old_listen_addrs.iter().find(|(i, ..)| i == ip).is_none() {
-            debug!("New listen address: {}", ma);
-            pending.push_back(Ok(ListenerEvent::NewAddress(ma.clone())));
-        }
-    }
-
-    // We should now be able to find the local address, if not something
-    // is seriously wrong and we report an error.
-    if listen_addrs
-        .iter()
-        .find(|(ip, net, _)| ip == &socket_addr.ip() || net.contains(&socket_addr.ip()))
-        .is_none()
-    {
-        let msg = format!("{} does not match any listen address", socket_addr.ip());
-        return Err(io::Error::new(io::ErrorKind::Other, msg));
-    }
-
-    Ok(())
-}
+type Buffer = VecDeque<Result<ListenerEvent<Upgrade, io::Error>, io::Error>>;
 
 #[cfg(test)]
 mod tests {
-    use super::tor_address_string;
+    use super::{tor_address_string, TorTokioTcpConfig};
+    use crate::{onion::OnionV2Policy, testing};
+    use libp2p::core::{transport::TransportError, Transport};
 
     #[test]
     fn can_format_tor_address_v3() {
@@ -541,4 +725,180 @@ mod tests {
 
         assert_eq!(got, want);
     }
+
+    #[test]
+    fn passes_dns_names_to_tor() {
+        let multi = "/dns4/example.com/tcp/4001"
+            .parse()
+            .expect("failed to parse multiaddr");
+        let want = "example.com:4001";
+        let got = tor_address_string(multi).expect("failed to stringify");
+
+        assert_eq!(got, want);
+    }
+
+    #[test]
+    fn rejects_dns_without_tcp_port() {
+        let multi = "/dns6/example.com/udp/4001"
+            .parse()
+            .expect("failed to parse multiaddr");
+
+        assert!(tor_address_string(multi).is_none());
+    }
+
+    #[test]
+    fn can_format_ip_addresses() {
+        let multi = "/ip6/::1/tcp/4001"
+            .parse()
+            .expect("failed to parse multiaddr");
+        let want = "[::1]:4001";
+        let got = tor_address_string(multi).expect("failed to stringify");
+
+        assert_eq!(got, want);
+    }
+
+    #[test]
+    fn clearnet_dialing_requires_opt_in() {
+        let multi: libp2p::Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+
+        match TorTokioTcpConfig::new().dial(multi.clone()) {
+            Err(TransportError::MultiaddrNotSupported(_)) => {}
+            _ => panic!("clearnet address dialed without opt-in"),
+        }
+        assert!(TorTokioTcpConfig::new()
+            .allow_clearnet(true)
+            .dial(multi)
+            .is_ok());
+    }
+
+    #[test]
+    fn ignores_trailing_peer_id() {
+        let multi = "/onion3/vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd:7/p2p/QmcgpsyWgH8Y8ajJz1Cu72KnS5uo2Aa2LpzU7kinSupNKC".parse().expect("failed to parse multiaddr");
+        let want = "vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd.onion:7";
+        let got = tor_address_string(multi).expect("failed to stringify");
+
+        assert_eq!(got, want);
+    }
+
+    #[test]
+    fn refuses_to_dial_bad_checksum() {
+        let multi: libp2p::Multiaddr =
+            "/onion3/vxw6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd:7"
+                .parse()
+                .unwrap();
+
+        match TorTokioTcpConfig::new().dial(multi) {
+            Err(TransportError::Other(e)) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput),
+            _ => panic!("dialed onion address with bad checksum"),
+        }
+    }
+
+    #[test]
+    fn v2_policy_is_applied() {
+        let multi: libp2p::Multiaddr = "/onion/aaimaq4ygg2iegci:80".parse().unwrap();
+
+        let reject = TorTokioTcpConfig::new().onion_v2(OnionV2Policy::Reject);
+        assert!(reject.dial(multi.clone()).is_err());
+
+        let allow = TorTokioTcpConfig::new().onion_v2(OnionV2Policy::Allow);
+        assert!(allow.dial(multi).is_ok());
+    }
+
+    #[tokio::test]
+    async fn listener_reports_onion_address() {
+        use futures::StreamExt;
+        use libp2p::core::transport::ListenerEvent;
+
+        let onion: libp2p::Multiaddr =
+            "/onion3/vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd:7"
+                .parse()
+                .unwrap();
+        let mut map = std::collections::HashMap::new();
+        map.insert(onion.clone(), 0);
+
+        let mut listener = TorTokioTcpConfig::new()
+            .onion_map(map)
+            .listen_on(onion.clone())
+            .expect("failed to listen");
+
+        match listener.next().await {
+            Some(Ok(ListenerEvent::NewAddress(addr))) => assert_eq!(addr, onion),
+            _ => panic!("expected NewAddress event"),
+        }
+    }
+
+    #[tokio::test]
+    async fn listener_reads_circuit_id_from_proxy_header() {
+        use tokio::io::AsyncWriteExt;
+
+        let onion: libp2p::Multiaddr =
+            "/onion3/vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd:7"
+                .parse()
+                .unwrap();
+        let config = TorTokioTcpConfig::new().proxy_protocol(true);
+        let port = crate::free_local_port().unwrap();
+        let mut map = std::collections::HashMap::new();
+        map.insert(onion.clone(), port);
+
+        let (mut listener, _) = testing::listen(config.onion_map(map), onion).await;
+
+        // A client that sends nothing does not hold up the next one.
+        let addr: std::net::SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+        let _silent = tokio::net::TcpStream::connect(&addr).await.unwrap();
+        let mut client = tokio::net::TcpStream::connect(&addr).await.unwrap();
+        client
+            .write_all(b"PROXY TCP6 fc00:dead:beef:4dad::2:a ::1 65535 7\r\n")
+            .await
+            .unwrap();
+
+        let _silent_upgrade = testing::next_upgrade(&mut listener).await;
+        let upgrade = testing::next_upgrade(&mut listener).await;
+        let stream = upgrade.await.unwrap();
+        assert_eq!(stream.circuit_id(), Some(0x0002_000a));
+    }
+
+    #[tokio::test]
+    async fn listener_refuses_connections_beyond_maximum() {
+        use futures::{future, StreamExt};
+        use tokio::io::AsyncReadExt;
+
+        let onion: libp2p::Multiaddr =
+            "/onion3/vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd:7"
+                .parse()
+                .unwrap();
+        let config = TorTokioTcpConfig::new().max_inbound(1).unwrap();
+        let port = crate::free_local_port().unwrap();
+        let mut map = std::collections::HashMap::new();
+        map.insert(onion.clone(), port);
+
+        let (mut listener, _) = testing::listen(config.onion_map(map), onion).await;
+
+        let addr: std::net::SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+        let _client = tokio::net::TcpStream::connect(&addr).await.unwrap();
+        let first = testing::next_upgrade(&mut listener).await.await.unwrap();
+
+        // The second connection is closed without an event.
+        let mut second = tokio::net::TcpStream::connect(&addr).await.unwrap();
+        let mut buf = [0u8; 1];
+        let read = Box::pin(second.read(&mut buf));
+        match future::select(listener.next(), read).await {
+            future::Either::Right((read, _)) => assert_eq!(read.unwrap(), 0),
+            future::Either::Left(_) => panic!("excess connection was not refused"),
+        }
+
+        // Closing the first connection frees its slot.
+        drop(first);
+        let _third = tokio::net::TcpStream::connect(&addr).await.unwrap();
+        let _third_upgrade = testing::next_upgrade(&mut listener).await;
+    }
+
+    #[test]
+    fn rejects_limits_that_never_admit_connections() {
+        assert!(TorTokioTcpConfig::new().max_inbound(0).is_err());
+        assert!(TorTokioTcpConfig::new().backlog(0).is_err());
+        assert!(TorTokioTcpConfig::new().backlog(-1).is_err());
+        assert!(TorTokioTcpConfig::new().accept_rate(0.0, 1).is_err());
+        assert!(TorTokioTcpConfig::new().accept_rate(1.0, 0).is_err());
+        assert!(TorTokioTcpConfig::new().accept_rate(1.0, 1).is_ok());
+    }
 }